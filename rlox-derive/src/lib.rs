@@ -0,0 +1,103 @@
+//! `#[derive(IntoValue, FromValue)]` for structs with named fields. A struct
+//! converts to a Lox map, with an entry for each field; re-exported from
+//! `rlox`, which see.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Implements `From<Struct> for rlox::Value`.
+#[proc_macro_derive(IntoValue)]
+pub fn derive_into_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_into_value(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Implements `TryFrom<rlox::Value> for Struct`. Missing entries are treated
+/// as nil, so that `Option` fields may be left out.
+#[proc_macro_derive(FromValue)]
+pub fn derive_from_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_from_value(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn named_fields(input: &DeriveInput) -> syn::Result<Vec<&syn::Ident>> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(fields
+                .named
+                .iter()
+                .map(|f| f.ident.as_ref().expect("named field"))
+                .collect()),
+            _ => Err(syn::Error::new_spanned(
+                &input.ident,
+                "only structs with named fields can be converted to and from Lox values",
+            )),
+        },
+        _ => Err(syn::Error::new_spanned(
+            &input.ident,
+            "only structs can be converted to and from Lox values",
+        )),
+    }
+}
+
+fn expand_into_value(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let fields = named_fields(input)?;
+    let keys = fields.iter().map(|f| f.to_string());
+
+    Ok(quote! {
+        impl #impl_generics ::std::convert::From<#name #ty_generics> for ::rlox::Value #where_clause {
+            fn from(value: #name #ty_generics) -> Self {
+                let mut entries = ::std::collections::BTreeMap::new();
+                #(
+                    entries.insert(#keys.to_string(), ::rlox::Value::from(value.#fields));
+                )*
+                ::rlox::Value::map(entries)
+            }
+        }
+    })
+}
+
+fn expand_from_value(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let fields = named_fields(input)?;
+    let keys = fields.iter().map(|f| f.to_string());
+
+    Ok(quote! {
+        impl #impl_generics ::std::convert::TryFrom<::rlox::Value> for #name #ty_generics #where_clause {
+            type Error = ::rlox::convert::ConversionError;
+
+            fn try_from(value: ::rlox::Value) -> ::std::result::Result<Self, Self::Error> {
+                let entries = match &value {
+                    ::rlox::Value::Map(entries) => entries.borrow(),
+                    _ => return ::std::result::Result::Err(::rlox::convert::ConversionError {
+                        expected: "map",
+                        actual: value.type_name(),
+                    }),
+                };
+                ::std::result::Result::Ok(#name {
+                    #(
+                        #fields: {
+                            let field = entries.get(#keys).cloned().unwrap_or(::rlox::Value::Nil);
+                            let actual = field.type_name();
+                            ::std::convert::TryFrom::try_from(field).map_err(|_| {
+                                ::rlox::convert::ConversionError {
+                                    expected: concat!("a valid '", #keys, "' field"),
+                                    actual,
+                                }
+                            })?
+                        },
+                    )*
+                })
+            }
+        }
+    })
+}
@@ -1,12 +1,18 @@
+use std::rc::Rc;
+
+/// Identifies a source in the interpreter's `SourceMap`.
+pub type FileId = usize;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Location {
+    pub file: FileId,
     pub start: usize,
     pub end: usize,
 }
 
-impl Into<std::ops::Range<usize>> for Location {
-    fn into(self) -> std::ops::Range<usize> {
-        self.start..self.end
+impl From<Location> for std::ops::Range<usize> {
+    fn from(val: Location) -> Self {
+        val.start..val.end
     }
 }
 
@@ -54,7 +60,22 @@ pub enum Expr {
     },
     Fun {
         params: Vec<String>,
-        body: Box<Stmt>,
+        body: Rc<Stmt>,
+        location: Location,
+    },
+    List {
+        elements: Vec<Expr>,
+        location: Location,
+    },
+    Index {
+        object: Box<Expr>,
+        index: Box<Expr>,
+        location: Location,
+    },
+    /// A name defined by a module, e.g. `util.name`.
+    Get {
+        object: Box<Expr>,
+        name: String,
         location: Location,
     },
 }
@@ -76,6 +97,9 @@ impl Locatable for Expr {
             Expr::Assignment { location, .. } => location,
             Expr::Call { location, .. } => location,
             Expr::Fun { location, .. } => location,
+            Expr::List { location, .. } => location,
+            Expr::Index { location, .. } => location,
+            Expr::Get { location, .. } => location,
         }
     }
 }
@@ -125,10 +149,20 @@ pub enum Stmt {
     FunDecl {
         name: String,
         params: Vec<String>,
-        body: Box<Stmt>,
+        body: Rc<Stmt>,
+        location: Location,
+    },
+    /// `import "path" as name;`
+    Import {
+        path: String,
+        name: String,
+        location: Location,
+    },
+    /// `{ ... }`; the location is that of the opening brace.
+    Block {
+        statements: Vec<Stmt>,
         location: Location,
     },
-    Block(Vec<Stmt>),
     If {
         cond: Expr,
         then: Box<Stmt>,
@@ -140,8 +174,204 @@ pub enum Stmt {
     },
 }
 
+// Expressions and statements are dropped without recursing, since a program
+// can be nested more deeply than the Rust stack allows. Each moves what's
+// inside it to a list, leaving placeholders, and empties those in turn.
+
+enum Owned {
+    Expr(Expr),
+    Stmt(Stmt),
+}
+
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        drop_all(pending);
+    }
+}
+
+impl Drop for Stmt {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        drop_all(pending);
+    }
+}
+
+/// Drops each of `pending` once it's been emptied, so that dropping it
+/// doesn't recurse.
+fn drop_all(mut pending: Vec<Owned>) {
+    while let Some(mut node) = pending.pop() {
+        match &mut node {
+            Owned::Expr(expr) => expr.take_children(&mut pending),
+            Owned::Stmt(statement) => statement.take_children(&mut pending),
+        }
+    }
+}
+
+fn take_expr(expr: &mut Expr) -> Expr {
+    let location = expr.location();
+    std::mem::replace(expr, Expr::Nil { location })
+}
+
+/// Takes a function's body, unless the function is still defined somewhere.
+fn take_body(body: &mut Rc<Stmt>, pending: &mut Vec<Owned>) {
+    if let Some(body) = Rc::get_mut(body) {
+        pending.push(Owned::Stmt(std::mem::replace(body, Stmt::Empty)));
+    }
+}
+
+impl Expr {
+    fn take_children(&mut self, pending: &mut Vec<Owned>) {
+        match self {
+            Expr::Nil { .. }
+            | Expr::Number { .. }
+            | Expr::Boolean { .. }
+            | Expr::String { .. }
+            | Expr::Var { .. } => {}
+            Expr::Unary { right, .. } => pending.push(Owned::Expr(take_expr(right))),
+            Expr::Binary { left, right, .. } => {
+                pending.push(Owned::Expr(take_expr(left)));
+                pending.push(Owned::Expr(take_expr(right)));
+            }
+            Expr::Assignment { rhs, .. } => pending.push(Owned::Expr(take_expr(rhs))),
+            Expr::Call { callee, args, .. } => {
+                pending.push(Owned::Expr(take_expr(callee)));
+                pending.extend(std::mem::take(args).into_iter().map(Owned::Expr));
+            }
+            Expr::Fun { body, .. } => take_body(body, pending),
+            Expr::List { elements, .. } => {
+                pending.extend(std::mem::take(elements).into_iter().map(Owned::Expr))
+            }
+            Expr::Index { object, index, .. } => {
+                pending.push(Owned::Expr(take_expr(object)));
+                pending.push(Owned::Expr(take_expr(index)));
+            }
+            Expr::Get { object, .. } => pending.push(Owned::Expr(take_expr(object))),
+        }
+    }
+}
+
+impl Stmt {
+    fn take_children(&mut self, pending: &mut Vec<Owned>) {
+        match self {
+            Stmt::Empty | Stmt::Import { .. } => {}
+            Stmt::Expr(expr) | Stmt::Print(expr) => pending.push(Owned::Expr(take_expr(expr))),
+            Stmt::Assert { expr, .. } | Stmt::Return { expr, .. } => {
+                pending.push(Owned::Expr(take_expr(expr)))
+            }
+            Stmt::VarDecl { init, .. } => pending.push(Owned::Expr(take_expr(init))),
+            Stmt::FunDecl { body, .. } => take_body(body, pending),
+            Stmt::Block { statements, .. } => {
+                pending.extend(std::mem::take(statements).into_iter().map(Owned::Stmt))
+            }
+            Stmt::If { cond, then, else_ } => {
+                pending.push(Owned::Expr(take_expr(cond)));
+                pending.push(Owned::Stmt(std::mem::replace(&mut **then, Stmt::Empty)));
+                pending.push(Owned::Stmt(std::mem::replace(&mut **else_, Stmt::Empty)));
+            }
+            Stmt::While { cond, body } => {
+                pending.push(Owned::Expr(take_expr(cond)));
+                pending.push(Owned::Stmt(std::mem::replace(&mut **body, Stmt::Empty)));
+            }
+        }
+    }
+}
+
+/// Finds the first `return` in `statements` that isn't inside a function,
+/// which would have nowhere to return to.
+pub fn find_top_level_return(statements: &[Stmt]) -> Option<Location> {
+    // The statements still to look at, next one last. This doesn't recurse,
+    // since a program can be nested more deeply than the Rust stack allows.
+    let mut pending: Vec<&Stmt> = statements.iter().rev().collect();
+    while let Some(statement) = pending.pop() {
+        match statement {
+            Stmt::Return { location, .. } => return Some(*location),
+            Stmt::Block { statements, .. } => pending.extend(statements.iter().rev()),
+            Stmt::If { then, else_, .. } => pending.extend([&**else_, &**then]),
+            Stmt::While { body, .. } => pending.push(body),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Whether any of `statements` defines a function, named or anonymous. Only
+/// functions keep hold of locations in a source after it has run.
+pub fn defines_functions(statements: &[Stmt]) -> bool {
+    any_function(statements.iter().map(Node::Stmt).collect())
+}
+
+/// Whether `expr` contains an anonymous function.
+pub fn expr_defines_functions(expr: &Expr) -> bool {
+    any_function(vec![Node::Expr(expr)])
+}
+
+enum Node<'a> {
+    Stmt(&'a Stmt),
+    Expr(&'a Expr),
+}
+
+/// Looks for a function among `pending` and everything inside them, without
+/// recursing, as `find_top_level_return` does.
+fn any_function(mut pending: Vec<Node>) -> bool {
+    while let Some(node) = pending.pop() {
+        match node {
+            Node::Stmt(statement) => match statement {
+                Stmt::Empty | Stmt::Import { .. } => {}
+                Stmt::FunDecl { .. } => return true,
+                Stmt::Expr(expr) | Stmt::Print(expr) => pending.push(Node::Expr(expr)),
+                Stmt::Assert { expr, .. } | Stmt::Return { expr, .. } => {
+                    pending.push(Node::Expr(expr))
+                }
+                Stmt::VarDecl { init, .. } => pending.push(Node::Expr(init)),
+                Stmt::Block { statements, .. } => pending.extend(statements.iter().map(Node::Stmt)),
+                Stmt::If { cond, then, else_ } => {
+                    pending.extend([Node::Expr(cond), Node::Stmt(then), Node::Stmt(else_)])
+                }
+                Stmt::While { cond, body } => {
+                    pending.extend([Node::Expr(cond), Node::Stmt(body)])
+                }
+            },
+            Node::Expr(expr) => match expr {
+                Expr::Nil { .. }
+                | Expr::Number { .. }
+                | Expr::Boolean { .. }
+                | Expr::String { .. }
+                | Expr::Var { .. } => {}
+                Expr::Fun { .. } => return true,
+                Expr::Unary { right, .. } => pending.push(Node::Expr(right)),
+                Expr::Binary { left, right, .. } => {
+                    pending.extend([Node::Expr(left), Node::Expr(right)])
+                }
+                Expr::Assignment { rhs, .. } => pending.push(Node::Expr(rhs)),
+                Expr::Call { callee, args, .. } => {
+                    pending.push(Node::Expr(callee));
+                    pending.extend(args.iter().map(Node::Expr));
+                }
+                Expr::List { elements, .. } => pending.extend(elements.iter().map(Node::Expr)),
+                Expr::Index { object, index, .. } => {
+                    pending.extend([Node::Expr(object), Node::Expr(index)])
+                }
+                Expr::Get { object, .. } => pending.push(Node::Expr(object)),
+            },
+        }
+    }
+    false
+}
+
+/// A location in the first source that an interpreter ran.
 pub fn location(s: usize, e: usize) -> Location {
-    Location { start: s, end: e }
+    location_in(0, s, e)
+}
+
+pub fn location_in(file: FileId, s: usize, e: usize) -> Location {
+    Location {
+        file,
+        start: s,
+        end: e,
+    }
 }
 
 pub fn desugar_for(
@@ -152,22 +382,29 @@ pub fn desugar_for(
     body: Stmt,
 ) -> Stmt {
     desugar_for_(
+        location,
         init.unwrap_or(Stmt::Empty),
         cond.unwrap_or(Expr::Boolean {
             value: true,
-            location: location,
+            location,
         }),
-        incr.unwrap_or(Expr::Nil { location: location }),
+        incr.unwrap_or(Expr::Nil { location }),
         body,
     )
 }
 
-pub fn desugar_for_(init: Stmt, cond: Expr, incr: Expr, body: Stmt) -> Stmt {
-    Stmt::Block(vec![
-        init,
-        Stmt::While {
-            cond: cond,
-            body: Box::new(Stmt::Block(vec![body, Stmt::Expr(incr)])),
-        },
-    ])
+pub fn desugar_for_(location: Location, init: Stmt, cond: Expr, incr: Expr, body: Stmt) -> Stmt {
+    Stmt::Block {
+        statements: vec![
+            init,
+            Stmt::While {
+                cond,
+                body: Box::new(Stmt::Block {
+                    statements: vec![body, Stmt::Expr(incr)],
+                    location,
+                }),
+            },
+        ],
+        location,
+    }
 }
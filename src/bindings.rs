@@ -1,15 +1,504 @@
+use crate::capabilities::{Capabilities, Capability};
+use crate::module;
 use crate::environment::Environment;
+use crate::convert::IntoNative;
+use crate::error::NativeError;
+use crate::format;
+use crate::json;
+use crate::runtime::Runtime;
+use std::convert::TryFrom;
+use std::io::Write;
+use crate::value::{Arity, NativeFn};
 use crate::Value;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-pub fn register_globals(environment: &mut Environment) {
-    let clock = Value::NativeFunction {
-        name: "clock".to_string(),
-        arity: 0,
-        fun: |_argv| {
-            let now = SystemTime::now();
-            Value::Number(now.duration_since(UNIX_EPOCH).unwrap().as_millis() as f64)
+
+/// Registers the native functions allowed by `capabilities`. `args` are the
+/// arguments passed to the script (i.e. those following `--` on the command
+/// line).
+pub fn register_globals(environment: &mut Environment, args: &[String], capabilities: &Capabilities) {
+    if allowed(environment, capabilities, Capability::Time, "clock") {
+        let runtime = environment.runtime().clone();
+        define_native(environment, "clock", 0, move |_argv| Ok(Value::Number(runtime.now())));
+    }
+
+    if allowed(environment, capabilities, Capability::Process, "args") {
+        let args: Vec<Value> = args.iter().map(|a| Value::String(a.to_string())).collect();
+        define_native(environment, "args", 0, move |_argv| {
+            Ok(Value::list(args.clone()))
+        });
+    }
+
+    if allowed(environment, capabilities, Capability::Env, "getenv") {
+        define_native(environment, "getenv", 1, |argv| match &argv[0] {
+            Value::String(name) => Ok(std::env::var(name)
+                .map(Value::String)
+                .unwrap_or(Value::Nil)),
+            _ => Err(NativeError::TypeMismatch { index: 0 }),
+        });
+    }
+
+    // exit doesn't exit directly; it unwinds back to whoever's running the
+    // script, so that they can clean up.
+    if allowed(environment, capabilities, Capability::Process, "exit") {
+        define_native(environment, "exit", 1, |argv| match argv[0] {
+            Value::Number(code) if code.fract() == 0.0 => Err(NativeError::Exit(code as i32)),
+            _ => Err(NativeError::TypeMismatch { index: 0 }),
+        });
+    }
+
+    if allowed(environment, capabilities, Capability::Time, RANDOM) {
+        define_random(environment);
+    }
+
+    define_native(environment, "len", 1, |argv| match &argv[0] {
+        Value::List(values) => Ok(Value::Number(values.borrow().len() as f64)),
+        Value::Map(entries) => Ok(Value::Number(entries.borrow().len() as f64)),
+        Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
+        _ => Err(NativeError::TypeMismatch { index: 0 }),
+    });
+
+    // Not a native, but it reads files, so it's recorded in the same way.
+    allowed(environment, capabilities, Capability::FsRead, module::IMPORT);
+
+    register_input(environment, capabilities);
+    register_files(environment, capabilities);
+    register_math(environment);
+    register_strings(environment);
+    register_conversions(environment);
+    register_regex(environment);
+    register_json(environment);
+}
+
+pub(crate) const RANDOM: &str = "random";
+
+/// Defines `random`. Unless it's seeded, it's as unpredictable as the clock,
+/// so it needs the same capability; see `Interpreter::seed_random`.
+pub(crate) fn define_random(environment: &mut Environment) {
+    let runtime = environment.runtime().clone();
+    define_native(environment, RANDOM, 0, move |_argv| {
+        Ok(Value::Number(runtime.random()))
+    });
+}
+
+/// Natives for reading the interpreter's input; see `runtime::Input`.
+fn register_input(environment: &mut Environment, capabilities: &Capabilities) {
+    if allowed(environment, capabilities, Capability::Stdin, "read_line") {
+        let runtime = environment.runtime().clone();
+        define_native(environment, "read_line", 0, move |_argv| read_line(&runtime));
+    }
+
+    if allowed(environment, capabilities, Capability::Stdin, "read_all") {
+        let runtime = environment.runtime().clone();
+        define_native(environment, "read_all", 0, move |_argv| {
+            runtime.read_all().map(Value::String).map_err(input_error)
+        });
+    }
+
+    // Like `read_line`, but first writes a prompt, if it's given one.
+    if allowed(environment, capabilities, Capability::Stdin, "input") {
+        let runtime = environment.runtime().clone();
+        define_native_with_arity(environment, "input", Arity::Between(0, 1), move |argv| {
+            if let Some(prompt) = argv.first() {
+                runtime.prompt(&prompt.to_string()).map_err(input_error)?;
+            }
+            read_line(&runtime)
+        });
+    }
+}
+
+/// The next line of input, or nil at the end.
+fn read_line(runtime: &Runtime) -> Result<Value, NativeError> {
+    let line = runtime.read_line().map_err(input_error)?;
+    Ok(line.into())
+}
+
+fn input_error(e: std::io::Error) -> NativeError {
+    NativeError::Io {
+        message: format!("cannot read input: {}", e),
+    }
+}
+
+/// Natives for reading and writing files. Paths are relative to the current
+/// directory, and failures are reported where the native was called.
+fn register_files(environment: &mut Environment, capabilities: &Capabilities) {
+    if allowed(environment, capabilities, Capability::FsRead, "read_file") {
+        register_fn(environment, "read_file", |path: String| {
+            std::fs::read_to_string(&path).map_err(|e| io_error("read", &path, e))
+        });
+    }
+    if allowed(environment, capabilities, Capability::FsRead, "read_lines") {
+        register_fn(environment, "read_lines", |path: String| {
+            let contents = std::fs::read_to_string(&path).map_err(|e| io_error("read", &path, e))?;
+            Ok::<_, NativeError>(contents.lines().map(String::from).collect::<Vec<_>>())
+        });
+    }
+    // The names of the entries in a directory, sorted.
+    if allowed(environment, capabilities, Capability::FsRead, "list_dir") {
+        register_fn(environment, "list_dir", |path: String| {
+            let list = |path: &str| -> std::io::Result<Vec<String>> {
+                let mut names = Vec::new();
+                for entry in std::fs::read_dir(path)? {
+                    names.push(entry?.file_name().to_string_lossy().into_owned());
+                }
+                names.sort();
+                Ok(names)
+            };
+            list(&path).map_err(|e| io_error("list", &path, e))
+        });
+    }
+    if allowed(environment, capabilities, Capability::FsRead, "file_exists") {
+        register_fn(environment, "file_exists", |path: String| {
+            std::path::Path::new(&path).exists()
+        });
+    }
+
+    if allowed(environment, capabilities, Capability::FsWrite, "write_file") {
+        register_fn(environment, "write_file", |path: String, contents: String| {
+            std::fs::write(&path, contents).map_err(|e| io_error("write", &path, e))
+        });
+    }
+    if allowed(environment, capabilities, Capability::FsWrite, "append_file") {
+        register_fn(environment, "append_file", |path: String, contents: String| {
+            let append = || -> std::io::Result<()> {
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?;
+                file.write_all(contents.as_bytes())
+            };
+            append().map_err(|e| io_error("append to", &path, e))
+        });
+    }
+    if allowed(environment, capabilities, Capability::FsWrite, "remove_file") {
+        register_fn(environment, "remove_file", |path: String| {
+            std::fs::remove_file(&path).map_err(|e| io_error("remove", &path, e))
+        });
+    }
+}
+
+fn io_error(action: &str, path: &str, e: std::io::Error) -> NativeError {
+    NativeError::Io {
+        message: format!("cannot {} '{}': {}", action, path, e),
+    }
+}
+
+/// Maths natives and constants. These are all pure; the typed arguments mean
+/// that passing anything but a number is reported at that argument.
+fn register_math(environment: &mut Environment) {
+    environment.define("PI", Value::Number(std::f64::consts::PI));
+    environment.define("E", Value::Number(std::f64::consts::E));
+
+    register_fn(environment, "sqrt", |x: f64| x.sqrt());
+    register_fn(environment, "pow", |x: f64, y: f64| x.powf(y));
+    register_fn(environment, "abs", |x: f64| x.abs());
+    register_fn(environment, "floor", |x: f64| x.floor());
+    register_fn(environment, "ceil", |x: f64| x.ceil());
+    register_fn(environment, "round", |x: f64| x.round());
+    register_fn(environment, "sin", |x: f64| x.sin());
+    register_fn(environment, "cos", |x: f64| x.cos());
+    register_fn(environment, "tan", |x: f64| x.tan());
+    register_fn(environment, "atan2", |y: f64, x: f64| y.atan2(x));
+    // Natural logarithm.
+    register_fn(environment, "log", |x: f64| x.ln());
+    register_fn(environment, "exp", |x: f64| x.exp());
+
+    define_variadic_native(environment, "min", 1, |argv| fold_numbers(&argv, f64::min));
+    define_variadic_native(environment, "max", 1, |argv| fold_numbers(&argv, f64::max));
+}
+
+fn fold_numbers(argv: &[Value], f: fn(f64, f64) -> f64) -> Result<Value, NativeError> {
+    let mut result: Option<f64> = None;
+    for (index, value) in argv.iter().enumerate() {
+        match value {
+            Value::Number(n) => result = Some(result.map_or(*n, |r| f(r, *n))),
+            _ => return Err(NativeError::TypeMismatch { index }),
+        }
+    }
+    Ok(Value::Number(result.unwrap_or(f64::NAN)))
+}
+
+/// String natives. Lengths and indexes count Unicode scalar values (`char`s),
+/// not bytes, and indexes start at zero.
+fn register_strings(environment: &mut Environment) {
+    // The characters from `start` up to, but not including, `end`; empty if
+    // `end` comes first.
+    register_fn(environment, "substr", |s: String, start: f64, end: f64| {
+        let length = s.chars().count();
+        let start = char_index(start, length, 1)?;
+        let end = char_index(end, length, 2)?;
+        let n = end.saturating_sub(start);
+        Ok::<_, NativeError>(s.chars().skip(start).take(n).collect::<String>())
+    });
+    // Where `needle` first appears in `s`, or nil if it doesn't.
+    register_fn(environment, "index_of", |s: String, needle: String| {
+        s.find(&needle).map(|byte| s[..byte].chars().count())
+    });
+    register_fn(environment, "contains", |s: String, needle: String| s.contains(&needle));
+    register_fn(environment, "starts_with", |s: String, prefix: String| s.starts_with(&prefix));
+    register_fn(environment, "ends_with", |s: String, suffix: String| s.ends_with(&suffix));
+    register_fn(environment, "upper", |s: String| s.to_uppercase());
+    register_fn(environment, "lower", |s: String| s.to_lowercase());
+    register_fn(environment, "trim", |s: String| s.trim().to_string());
+    // An empty separator splits `s` into its characters.
+    register_fn(environment, "split", |s: String, separator: String| {
+        if separator.is_empty() {
+            s.chars().map(String::from).collect::<Vec<_>>()
+        } else {
+            s.split(&separator).map(String::from).collect()
+        }
+    });
+    // These work out how long the result will be before building it.
+    let runtime = environment.runtime().clone();
+    register_fn(environment, "join", move |parts: Vec<String>, separator: String| {
+        let separators = separator.len().saturating_mul(parts.len().saturating_sub(1));
+        let length = parts.iter().fold(separators, |sum, part| sum.saturating_add(part.len()));
+        check_length(&runtime, length, 0)?;
+        Ok(parts.join(&separator))
+    });
+    let runtime = environment.runtime().clone();
+    register_fn(environment, "replace", move |s: String, from: String, to: String| {
+        let count = s.matches(from.as_str()).count();
+        let length = (s.len() - count * from.len()).saturating_add(count.saturating_mul(to.len()));
+        check_length(&runtime, length, 2)?;
+        Ok(s.replace(&from, &to))
+    });
+    let runtime = environment.runtime().clone();
+    register_fn(environment, "repeat", move |s: String, n: usize| {
+        check_length(&runtime, s.len().saturating_mul(n), 1)?;
+        Ok(s.repeat(n))
+    });
+}
+
+/// Fails with an `InvalidArgument` at `index` if a native shouldn't build a
+/// string of `bytes` bytes; see `Runtime::check_string_length`.
+fn check_length(runtime: &Runtime, bytes: usize, index: usize) -> Result<(), NativeError> {
+    runtime
+        .check_string_length(bytes)
+        .map_err(|message| NativeError::InvalidArgument { index, message })
+}
+
+/// Conversions between values and strings.
+fn register_conversions(environment: &mut Environment) {
+    // The same as `print` would print.
+    define_native(environment, "str", 1, |argv| Ok(Value::String(argv[0].to_string())));
+    // A number, or nil if the string isn't one.
+    define_native(environment, "num", 1, |argv| match &argv[0] {
+        Value::Number(n) => Ok(Value::Number(*n)),
+        Value::String(s) => Ok(parse_number(s).map(Value::Number).unwrap_or(Value::Nil)),
+        _ => Err(NativeError::TypeMismatch { index: 0 }),
+    });
+    let runtime = environment.runtime().clone();
+    define_variadic_native(environment, "format", 1, move |argv| match &argv[0] {
+        Value::String(fmt) => format::format_checked(fmt, &argv[1..], |length| {
+            runtime.check_string_length(length)
+        })
+            .map(Value::String)
+            .map_err(|e| NativeError::InvalidArgument {
+                index: e.argument,
+                message: e.message,
+            }),
+        _ => Err(NativeError::TypeMismatch { index: 0 }),
+    });
+}
+
+/// Parses a number the way a Lox literal would be written, allowing
+/// surrounding whitespace, a sign and an exponent. Unlike `str::parse`, it
+/// doesn't accept "inf" or "nan".
+fn parse_number(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let valid = s
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'));
+    if !valid || !s.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Regular expression natives, using the `regex` crate's syntax. Each takes
+/// the pattern first; compiled patterns are cached by the runtime.
+fn register_regex(environment: &mut Environment) {
+    let runtime = environment.runtime().clone();
+    let regex = move |pattern: &str| {
+        runtime.regex(pattern).map_err(|e| NativeError::InvalidArgument {
+            index: 0,
+            message: regex_error_message(&e),
+        })
+    };
+
+    // Whether the pattern matches anywhere in `s`.
+    let re = regex.clone();
+    register_fn(environment, "re_match", move |pattern: String, s: String| {
+        Ok::<_, NativeError>(re(&pattern)?.is_match(&s))
+    });
+    let re = regex.clone();
+    register_fn(environment, "re_find_all", move |pattern: String, s: String| {
+        let regex = re(&pattern)?;
+        let matches: Vec<String> = regex.find_iter(&s).map(|m| m.as_str().to_string()).collect();
+        Ok::<_, NativeError>(matches)
+    });
+    // Replaces every match; `$1` or `${name}` in the replacement refer to
+    // capture groups.
+    let re = regex.clone();
+    let runtime = environment.runtime().clone();
+    register_fn(
+        environment,
+        "re_replace",
+        move |pattern: String, s: String, replacement: String| {
+            let regex = re(&pattern)?;
+            // `$name` is at least two characters, and stands for no more than
+            // the match, so that bounds how long each replacement can be.
+            let expands = replacement.contains('$');
+            let mut result = String::new();
+            let mut last = 0;
+            for captures in regex.captures_iter(&s) {
+                let m = captures.get(0).expect("group 0 is the whole match");
+                let most = if expands {
+                    replacement.len().saturating_mul(m.len().max(1))
+                } else {
+                    replacement.len()
+                };
+                let length = result.len() + (m.start() - last) + most;
+                check_length(&runtime, length, 2)?;
+                result.push_str(&s[last..m.start()]);
+                captures.expand(&replacement, &mut result);
+                last = m.end();
+            }
+            result.push_str(&s[last..]);
+            Ok::<_, NativeError>(result)
         },
+    );
+    let re = regex.clone();
+    register_fn(environment, "re_split", move |pattern: String, s: String| {
+        let parts: Vec<String> = re(&pattern)?.split(&s).map(String::from).collect();
+        Ok::<_, NativeError>(parts)
+    });
+    // The first match's capture groups, as a list starting with the whole
+    // match, with nil for groups that didn't take part; or nil if there's no
+    // match.
+    let re = regex;
+    register_fn(environment, "re_captures", move |pattern: String, s: String| {
+        let captures = re(&pattern)?.captures(&s).map(|captures| {
+            captures
+                .iter()
+                .map(|group| group.map(|m| m.as_str().to_string()))
+                .collect::<Vec<_>>()
+        });
+        Ok::<_, NativeError>(captures)
+    });
+}
+
+fn register_json(environment: &mut Environment) {
+    define_native(environment, "json_parse", 1, |argv| match &argv[0] {
+        Value::String(text) => json::parse(text).map_err(|e| NativeError::InvalidArgument {
+            index: 0,
+            message: e.to_string(),
+        }),
+        _ => Err(NativeError::TypeMismatch { index: 0 }),
+    });
+    // The optional second argument is how many spaces to indent by.
+    define_native_with_arity(environment, "json_stringify", Arity::Between(1, 2), |argv| {
+        let indent = match argv.get(1) {
+            Some(indent) => usize::try_from(indent.clone())
+                .map_err(|_| NativeError::TypeMismatch { index: 1 })?,
+            None => 0,
+        };
+        if indent > json::MAX_INDENT {
+            return Err(NativeError::InvalidArgument {
+                index: 1,
+                message: format!("the indent can be at most {}", json::MAX_INDENT),
+            });
+        }
+        json::stringify(&argv[0], indent)
+            .map(Value::String)
+            .map_err(|e| NativeError::InvalidArgument {
+                index: 0,
+                message: e.to_string(),
+            })
+    });
+}
+
+/// The `regex` crate's syntax errors draw a diagram over several lines;
+/// diagnostics already point at the pattern, so just keep the explanation.
+fn regex_error_message(e: &regex::Error) -> String {
+    let message = e.to_string();
+    message
+        .lines()
+        .find_map(|line| line.strip_prefix("error: "))
+        .unwrap_or(&message)
+        .to_string()
+}
+
+/// Checks that `index`, the argument at position `argument`, is a whole
+/// number between zero and `length` (inclusive, since it might be the end of
+/// a range).
+fn char_index(index: f64, length: usize, argument: usize) -> Result<usize, NativeError> {
+    if index.fract() != 0.0 || index < 0.0 || index > length as f64 {
+        return Err(NativeError::IndexOutOfRange {
+            argument,
+            index,
+            length,
+        });
+    }
+    Ok(index as usize)
+}
+
+/// Returns whether the native `name`, which needs `capability`, should be
+/// defined. If not, remembers why, so that using it can say so.
+fn allowed(
+    environment: &Environment,
+    capabilities: &Capabilities,
+    capability: Capability,
+    name: &str,
+) -> bool {
+    if capabilities.contains(capability) {
+        return true;
+    }
+    environment.runtime().disable(name, capability);
+    false
+}
+
+/// Defines a native function, which takes exactly `arity` arguments.
+pub fn define_native(
+    environment: &mut Environment,
+    name: &str,
+    arity: usize,
+    fun: impl Fn(Vec<Value>) -> Result<Value, NativeError> + 'static,
+) {
+    define_native_with_arity(environment, name, Arity::Exactly(arity), fun);
+}
+
+/// Defines a native function, which takes at least `min_arity` arguments.
+pub fn define_variadic_native(
+    environment: &mut Environment,
+    name: &str,
+    min_arity: usize,
+    fun: impl Fn(Vec<Value>) -> Result<Value, NativeError> + 'static,
+) {
+    define_native_with_arity(environment, name, Arity::AtLeast(min_arity), fun);
+}
+
+/// Defines a native function; it's only called with a number of arguments
+/// that `arity` accepts.
+pub fn define_native_with_arity(
+    environment: &mut Environment,
+    name: &str,
+    arity: Arity,
+    fun: impl Fn(Vec<Value>) -> Result<Value, NativeError> + 'static,
+) {
+    let native = Value::NativeFunction {
+        name: name.to_string(),
+        arity,
+        fun: NativeFn::new(fun),
+    };
+    environment.define(name, native);
+}
+
+/// Defines a native function with typed arguments; see `convert::IntoNative`.
+pub fn register_fn<Args>(environment: &mut Environment, name: &str, fun: impl IntoNative<Args>) {
+    let native = Value::NativeFunction {
+        name: name.to_string(),
+        arity: Arity::Exactly(fun.arity()),
+        fun: fun.into_native(),
     };
-    environment.define("clock", clock);
-}
\ No newline at end of file
+    environment.define(name, native);
+}
@@ -0,0 +1,133 @@
+//! What scripts are allowed to do. Natives that reach outside the
+//! interpreter (reading the clock or the environment, exiting the process,
+//! and so on) each need a capability, and are only defined if it's enabled.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::iter::FromIterator;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    /// Natives with no side-effects, such as `len`. These are always enabled.
+    Pure,
+    /// Reading the clock, and unseeded random numbers.
+    Time,
+    /// Reading files.
+    FsRead,
+    /// Writing files.
+    FsWrite,
+    /// Reading environment variables.
+    Env,
+    /// The process's arguments, and exiting.
+    Process,
+    /// Reading the interpreter's input, which is stdin unless it's been
+    /// redirected with `Interpreter::set_input`.
+    Stdin,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::Pure,
+        Capability::Time,
+        Capability::FsRead,
+        Capability::FsWrite,
+        Capability::Env,
+        Capability::Process,
+        Capability::Stdin,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Pure => "pure",
+            Capability::Time => "time",
+            Capability::FsRead => "fs-read",
+            Capability::FsWrite => "fs-write",
+            Capability::Env => "env",
+            Capability::Process => "process",
+            Capability::Stdin => "stdin",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL
+            .iter()
+            .find(|c| c.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = Capability::ALL.iter().map(Capability::name).collect();
+                format!("unknown capability '{}'; expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// A set of capabilities. `Pure` is always included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    enabled: BTreeSet<Capability>,
+}
+
+impl Capabilities {
+    /// Everything; this is what the command line uses by default.
+    pub fn all() -> Self {
+        Capability::ALL.iter().copied().collect()
+    }
+
+    /// Only natives without side-effects.
+    pub fn pure() -> Self {
+        std::iter::empty().collect()
+    }
+
+    pub fn with(mut self, capability: Capability) -> Self {
+        self.enabled.insert(capability);
+        self
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.enabled.contains(&capability)
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        let mut enabled: BTreeSet<Capability> = iter.into_iter().collect();
+        enabled.insert(Capability::Pure);
+        Self { enabled }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_names() {
+        assert_eq!("fs-read".parse(), Ok(Capability::FsRead));
+        assert!("network".parse::<Capability>().is_err());
+    }
+
+    #[test]
+    fn pure_is_always_included() {
+        assert!(Capabilities::pure().contains(Capability::Pure));
+        assert!(!Capabilities::pure().contains(Capability::Time));
+        let caps: Capabilities = vec![Capability::Env].into_iter().collect();
+        assert!(caps.contains(Capability::Pure));
+        assert!(caps.contains(Capability::Env));
+    }
+}
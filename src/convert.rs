@@ -0,0 +1,388 @@
+//! Conversions between `Value` and Rust types, and adapters for registering
+//! Rust functions with typed signatures as natives.
+//!
+//! Rust values convert into `Value` with `From`; `()` and `Option::None`
+//! become nil, `Vec` becomes a list and `HashMap` becomes a map. Going the
+//! other way uses `TryFrom`, which fails with a `ConversionError` if the value
+//! has the wrong type or, for integers, is out of range.
+
+use crate::error::NativeError;
+use crate::value::NativeFn;
+use crate::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    /// What we were trying to convert to, e.g. "number" or "u8".
+    pub expected: &'static str,
+    /// The type of the value we were given.
+    pub actual: &'static str,
+}
+
+impl ConversionError {
+    fn new(expected: &'static str, value: &Value) -> Self {
+        ConversionError {
+            expected,
+            actual: value.type_name(),
+        }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {}, got {}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {$(
+        impl From<$t> for Value {
+            fn from(n: $t) -> Self {
+                Value::Number(n as f64)
+            }
+        }
+
+        impl TryFrom<Value> for $t {
+            type Error = ConversionError;
+
+            fn try_from(value: Value) -> Result<Self, Self::Error> {
+                // `MAX as f64` rounds up for 64-bit types, so compare against
+                // the power of two just past it instead.
+                let limit = 2f64.powi(<$t>::MAX.count_ones() as i32);
+                match value {
+                    Value::Number(n) if n.fract() == 0.0 && n >= <$t>::MIN as f64 && n < limit => {
+                        Ok(n as $t)
+                    }
+                    _ => Err(ConversionError::new(stringify!($t), &value)),
+                }
+            }
+        }
+    )*};
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl TryFrom<Value> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Number(n) => Ok(n),
+            _ => Err(ConversionError::new("number", &value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            _ => Err(ConversionError::new("boolean", &value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::String(s) => Ok(s),
+            _ => Err(ConversionError::new("string", &value)),
+        }
+    }
+}
+
+/// For natives that don't return anything.
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Nil
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(option: Option<T>) -> Self {
+        option.map_or(Value::Nil, Into::into)
+    }
+}
+
+// A blanket `impl<T: TryFrom<Value>> TryFrom<Value> for Option<T>` would
+// conflict with the standard library's `From<T> for Option<T>`, so list the
+// types instead.
+macro_rules! option_conversions {
+    ($($t:ty),*) => {$(
+        impl TryFrom<Value> for Option<$t> {
+            type Error = ConversionError;
+
+            fn try_from(value: Value) -> Result<Self, Self::Error> {
+                match value {
+                    Value::Nil => Ok(None),
+                    _ => <$t>::try_from(value).map(Some),
+                }
+            }
+        }
+    )*};
+}
+
+option_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f64, bool, String);
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::list(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: TryFrom<Value, Error = ConversionError>> TryFrom<Value> for Vec<T> {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match &value {
+            Value::List(values) => values
+                .borrow()
+                .iter()
+                .map(|v| T::try_from(v.clone()))
+                .collect(),
+            _ => Err(ConversionError::new("list", &value)),
+        }
+    }
+}
+
+impl<T: Into<Value>> From<HashMap<String, T>> for Value {
+    fn from(entries: HashMap<String, T>) -> Self {
+        Value::map(entries.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+impl<T: TryFrom<Value, Error = ConversionError>> TryFrom<Value> for HashMap<String, T> {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match &value {
+            Value::Map(entries) => entries
+                .borrow()
+                .iter()
+                .map(|(k, v)| Ok((k.to_string(), T::try_from(v.clone())?)))
+                .collect(),
+            _ => Err(ConversionError::new("map", &value)),
+        }
+    }
+}
+
+/// The result of a typed native function: anything that converts into a
+/// `Value`, or a `Result` of one.
+pub trait NativeResult {
+    fn into_result(self) -> Result<Value, NativeError>;
+}
+
+impl<T: Into<Value>> NativeResult for T {
+    fn into_result(self) -> Result<Value, NativeError> {
+        Ok(self.into())
+    }
+}
+
+impl<T: Into<Value>> NativeResult for Result<T, NativeError> {
+    fn into_result(self) -> Result<Value, NativeError> {
+        self.map(Into::into)
+    }
+}
+
+/// A Rust function that can be registered as a native with
+/// `Interpreter::register_fn`. It's implemented for closures of up to six
+/// arguments, each of which must implement `TryFrom<Value>`. If an argument
+/// can't be converted, the call fails with a `TypeMismatch` at that argument.
+pub trait IntoNative<Args> {
+    fn arity(&self) -> usize;
+    fn into_native(self) -> NativeFn;
+}
+
+macro_rules! into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: NativeResult,
+            $($arg: TryFrom<Value>),*
+        {
+            fn arity(&self) -> usize {
+                let args: &[&str] = &[$(stringify!($arg)),*];
+                args.len()
+            }
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn into_native(self) -> NativeFn {
+                NativeFn::new(move |argv| {
+                    let mut argv = argv.into_iter().enumerate();
+                    $(
+                        let (index, value) = argv.next().expect("arity is checked by the caller");
+                        let $arg = $arg::try_from(value)
+                            .map_err(|_| NativeError::TypeMismatch { index })?;
+                    )*
+                    (self)($($arg),*).into_result()
+                })
+            }
+        }
+    };
+}
+
+into_native!();
+into_native!(A);
+into_native!(A, B);
+into_native!(A, B, C);
+into_native!(A, B, C, D);
+into_native!(A, B, C, D, E);
+into_native!(A, B, C, D, E, G);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Error, FromValue, Interpreter, IntoValue, RuntimeError};
+
+    #[test]
+    fn integers_are_range_checked() {
+        assert_eq!(u8::try_from(Value::Number(255.0)), Ok(255));
+        assert!(u8::try_from(Value::Number(256.0)).is_err());
+        assert!(u8::try_from(Value::Number(-1.0)).is_err());
+        assert!(i32::try_from(Value::Number(1.5)).is_err());
+        assert_eq!(i64::try_from(Value::Number(-3.0)), Ok(-3));
+    }
+
+    #[test]
+    fn integer_range_ends_are_exact() {
+        let two_to_the = |n| Value::Number(2f64.powi(n));
+        assert_eq!(i64::try_from(Value::Number(-(2f64.powi(63)))), Ok(i64::MIN));
+        assert!(i64::try_from(two_to_the(63)).is_err());
+        assert!(isize::try_from(two_to_the(isize::BITS as i32 - 1)).is_err());
+        assert_eq!(u64::try_from(two_to_the(63)), Ok(1 << 63));
+        assert!(u64::try_from(two_to_the(64)).is_err());
+        assert!(usize::try_from(two_to_the(usize::BITS as i32)).is_err());
+        assert_eq!(u32::try_from(Value::Number(u32::MAX as f64)), Ok(u32::MAX));
+        assert!(u32::try_from(two_to_the(32)).is_err());
+        assert!(i8::try_from(Value::Number(128.0)).is_err());
+        assert_eq!(i8::try_from(Value::Number(-128.0)), Ok(-128));
+    }
+
+    #[test]
+    fn conversion_error_names_types() {
+        let e = f64::try_from(Value::Boolean(true)).unwrap_err();
+        assert_eq!(e.to_string(), "expected number, got boolean");
+    }
+
+    #[test]
+    fn option_is_nil() {
+        assert_eq!(Value::from(None::<f64>), Value::Nil);
+        assert_eq!(Value::from(Some("x")), Value::String("x".to_string()));
+        assert_eq!(Option::<String>::try_from(Value::Nil), Ok(None));
+        assert_eq!(Option::<bool>::try_from(Value::Boolean(true)), Ok(Some(true)));
+    }
+
+    #[test]
+    fn vec_round_trip() {
+        let value = Value::from(vec![1, 2, 3]);
+        assert_eq!(Vec::<u32>::try_from(value), Ok(vec![1, 2, 3]));
+        let e = Vec::<String>::try_from(Value::from(vec![1])).unwrap_err();
+        assert_eq!(e.to_string(), "expected string, got number");
+    }
+
+    #[test]
+    fn hash_map_round_trip() {
+        let mut entries = HashMap::new();
+        entries.insert("a".to_string(), true);
+        let value = Value::from(entries.clone());
+        assert_eq!(HashMap::<String, bool>::try_from(value), Ok(entries));
+        let mut entries = HashMap::new();
+        entries.insert("a".to_string(), 1.5);
+        let e = HashMap::<String, u8>::try_from(Value::from(entries)).unwrap_err();
+        assert_eq!(e.to_string(), "expected u8, got number");
+    }
+
+    #[test]
+    fn register_typed_function() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("repeat", |s: String, n: usize| s.repeat(n));
+        assert_eq!(
+            interpreter.eval_expr(r#"repeat("ab", 3)"#),
+            Ok(Value::String("ababab".to_string()))
+        );
+        assert!(matches!(
+            interpreter.eval_expr(r#"repeat("ab")"#),
+            Err(Error::Runtime(RuntimeError::ArityMismatch {
+                expected: crate::value::Arity::Exactly(2),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn typed_function_conversion_failure_is_located() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("half", |n: f64| n / 2.0);
+        assert_eq!(
+            interpreter.eval_expr(r#"half("four")"#),
+            Err(Error::Runtime(RuntimeError::TypeMismatch {
+                location: crate::ast::location(5, 11)
+            }))
+        );
+    }
+
+    #[derive(Debug, PartialEq, IntoValue, FromValue)]
+    struct Point {
+        x: f64,
+        y: f64,
+        label: Option<String>,
+    }
+
+    #[test]
+    fn derived_struct_round_trip() {
+        let point = Point {
+            x: 1.0,
+            y: 2.0,
+            label: None,
+        };
+        let value = Value::from(point);
+        assert_eq!(value.to_string(), r#"{"label": <nil>, "x": 1, "y": 2}"#);
+        assert_eq!(
+            Point::try_from(value),
+            Ok(Point {
+                x: 1.0,
+                y: 2.0,
+                label: None
+            })
+        );
+    }
+
+    #[test]
+    fn derived_struct_in_lox() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_global(
+            "p",
+            Point {
+                x: 3.0,
+                y: 4.0,
+                label: Some("origin".to_string()),
+            },
+        );
+        assert_eq!(
+            interpreter.eval_expr(r#"p["x"] * p["x"] + p["y"] * p["y"]"#),
+            Ok(Value::Number(25.0))
+        );
+        assert_eq!(
+            interpreter.eval_expr(r#"p["label"]"#),
+            Ok(Value::from("origin"))
+        );
+    }
+
+    #[test]
+    fn derived_struct_rejects_wrong_field_type() {
+        let mut entries = std::collections::BTreeMap::new();
+        entries.insert("x".to_string(), Value::from("one"));
+        let e = Point::try_from(Value::map(entries)).unwrap_err();
+        assert_eq!(e.to_string(), "expected a valid 'x' field, got string");
+        assert!(Point::try_from(Value::Nil).is_err());
+    }
+}
@@ -1,18 +1,25 @@
+use crate::runtime::Runtime;
 use crate::Value;
 use std::cell::RefCell;
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt::{Debug, Error, Formatter};
 use std::rc::Rc;
 
-struct Scope {
-    values: HashMap<String, Value>,
+pub(crate) struct Scope {
+    // Each value, with the size it was accounted for at when it was stored;
+    // lists and maps can change size afterwards.
+    values: HashMap<String, (Value, usize)>,
     enclosing: Option<Environment>,
+    // How many bytes this scope has reported to the runtime, so that it can
+    // give them back when it's dropped.
+    size: usize,
+    runtime: Rc<Runtime>,
 }
 
 #[derive(Clone)]
 pub struct Environment {
     scope: Rc<RefCell<Scope>>,
+    runtime: Rc<Runtime>,
 }
 
 impl Debug for Environment {
@@ -29,73 +36,186 @@ impl PartialEq for Environment {
     }
 }
 
+fn entry_size(name: &str, value: &Value) -> usize {
+    std::mem::size_of::<String>() + name.len() + value.heap_size()
+}
+
 impl Scope {
-    pub fn new() -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Self {
+    pub fn new(enclosing: Option<Environment>, runtime: Rc<Runtime>) -> Rc<RefCell<Self>> {
+        let size = std::mem::size_of::<Self>();
+        runtime.allocate(size);
+        let heap_runtime = runtime.clone();
+        let scope = Rc::new(RefCell::new(Self {
             values: HashMap::new(),
-            enclosing: None,
-        }))
+            enclosing,
+            size,
+            runtime,
+        }));
+        heap_runtime.heap().register(&scope);
+        scope
     }
 
-    pub fn with_enclosing(enclosing: Environment) -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Self {
-            values: HashMap::new(),
-            enclosing: Some(enclosing),
-        }))
+    pub(crate) fn enclosing(&self) -> Option<&Environment> {
+        self.enclosing.as_ref()
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.values().map(|(value, _)| value)
+    }
+
+    /// Empties the scope, for the garbage collector.
+    pub(crate) fn clear(&mut self) -> (HashMap<String, (Value, usize)>, Option<Environment>) {
+        let values = std::mem::take(&mut self.values);
+        for (value, size) in values.values() {
+            self.released(value, *size);
+        }
+        (values, self.enclosing.take())
+    }
+
+    /// Accounts for a value being stored in this scope, returning its size.
+    fn stored(&mut self, name: &str, value: &Value) -> usize {
+        let size = entry_size(name, value);
+        self.size += size;
+        self.runtime.allocate(size);
+        self.runtime.heap().value_stored(value);
+        size
+    }
+
+    /// Accounts for a value that was stored with `size` being removed from
+    /// this scope.
+    fn released(&mut self, value: &Value, size: usize) {
+        self.size -= size;
+        self.runtime.free(size);
+        self.runtime.heap().value_released(value);
     }
 
     pub fn define(&mut self, name: &str, value: Value) {
-        self.values.insert(name.to_string(), value);
+        let size = self.stored(name, &value);
+        if let Some((old, old_size)) = self.values.insert(name.to_string(), (value, size)) {
+            self.released(&old, old_size);
+        }
     }
 
     pub fn assign(&mut self, name: &str, value: Value) -> Result<Value, ()> {
-        match self.values.entry(name.to_string()) {
-            Entry::Occupied(mut entry) => {
-                entry.insert(value.clone());
-                Ok(value)
-            }
-            Entry::Vacant(_) => match &mut self.enclosing {
+        if !self.values.contains_key(name) {
+            return match &mut self.enclosing {
                 None => Err(()),
                 Some(e) => e.assign(name, value),
-            },
+            };
         }
+        let size = self.stored(name, &value);
+        let slot = self.values.get_mut(name).expect("checked above");
+        let (old, old_size) = std::mem::replace(slot, (value.clone(), size));
+        self.released(&old, old_size);
+        Ok(value)
     }
 
     pub fn get(&self, name: &str) -> Option<Value> {
         match self.values.get(name) {
-            Some(v) => Some(v.clone()),
+            Some((v, _)) => Some(v.clone()),
             None => match &self.enclosing {
                 None => None,
                 Some(e) => e.get(name),
             },
         }
     }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names = match &self.enclosing {
+            None => Vec::new(),
+            Some(e) => e.names(),
+        };
+        for name in self.values.keys() {
+            if !names.contains(name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        for (value, size) in std::mem::take(&mut self.values).into_values() {
+            self.released(&value, size);
+        }
+        self.runtime.free(self.size);
+        self.runtime.heap().scope_dropped();
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Environment {
     pub fn new() -> Self {
+        let runtime = Rc::new(Runtime::default());
         Self {
-            scope: Scope::new(),
+            scope: Scope::new(None, runtime.clone()),
+            runtime,
         }
     }
 
     pub fn with_enclosing(enclosing: &Environment) -> Self {
         Self {
-            scope: Scope::with_enclosing(enclosing.clone()),
+            scope: Scope::new(Some(enclosing.clone()), enclosing.runtime.clone()),
+            runtime: enclosing.runtime.clone(),
         }
     }
 
+    /// The outermost environment, which holds the natives.
+    pub(crate) fn root(&self) -> Environment {
+        let mut environment = self.clone();
+        loop {
+            let enclosing = environment.scope.borrow().enclosing().cloned();
+            match enclosing {
+                Some(enclosing) => environment = enclosing,
+                None => return environment,
+            }
+        }
+    }
+
+    pub(crate) fn scope(&self) -> &Rc<RefCell<Scope>> {
+        &self.scope
+    }
+
+    /// The runtime shared by this environment and all those enclosing it.
+    /// Whether the two are the same environment, rather than equivalent ones.
+    pub(crate) fn same(&self, other: &Environment) -> bool {
+        Rc::ptr_eq(&self.scope, &other.scope)
+    }
+
+    pub fn runtime(&self) -> &Rc<Runtime> {
+        &self.runtime
+    }
+
     pub fn define(&mut self, name: &str, value: Value) {
         self.scope.borrow_mut().define(name, value);
     }
 
-    pub fn assign(&mut self, name: &str, value: Value) -> Result<Value, ()> {
+    pub(crate) fn assign(&mut self, name: &str, value: Value) -> Result<Value, ()> {
         self.scope.borrow_mut().assign(name, value)
     }
 
     pub fn get(&self, name: &str) -> Option<Value> {
         self.scope.borrow().get(name)
     }
+
+    /// Like `get`, but doesn't look in enclosing scopes.
+    pub(crate) fn get_local(&self, name: &str) -> Option<Value> {
+        self.scope.borrow().values.get(name).map(|(value, _)| value.clone())
+    }
+
+    /// Returns every name visible from this environment, including those
+    /// in enclosing scopes, sorted alphabetically.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.scope.borrow().names();
+        names.sort();
+        names
+    }
 }
 
 #[cfg(test)]
@@ -167,4 +287,34 @@ mod test {
         }
         assert_eq!(e.get("a"), Some(Value::String("outer a".to_string())));
     }
+
+    #[test]
+    fn names_includes_outer() {
+        let mut e = Environment::new();
+        e.define("b", Value::Nil);
+        e.define("a", Value::Nil);
+        {
+            let mut e = Environment::with_enclosing(&e);
+            e.define("c", Value::Nil);
+            e.define("a", Value::Nil);
+            assert_eq!(e.names(), vec!["a", "b", "c"]);
+        }
+        assert_eq!(e.names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn list_that_grows_after_being_stored_is_released() {
+        let e = Environment::new();
+        let before = e.runtime().memory_usage();
+        let list = Value::list(vec![]);
+        {
+            let mut inner = Environment::with_enclosing(&e);
+            inner.define("xs", list.clone());
+            inner.assign("xs", list.clone()).unwrap();
+            if let Value::List(values) = &list {
+                values.borrow_mut().extend((0..100).map(|n| Value::Number(n as f64)));
+            }
+        }
+        assert_eq!(e.runtime().memory_usage(), before);
+    }
 }
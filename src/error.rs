@@ -1,19 +1,49 @@
 use codespan_reporting::diagnostic::{Diagnostic, Label};
-use codespan_reporting::files::SimpleFiles;
+use codespan_reporting::files::{Files, SimpleFiles};
 use codespan_reporting::term::termcolor::{ColorChoice, StandardStream};
 
 use crate::ast;
+use crate::capabilities::Capability;
 use crate::lox;
+use crate::module;
+use crate::value::Arity;
+use crate::source_map::SourceMap;
 use crate::Value;
 
-type ParseError<'s> = lalrpop_util::ParseError<usize, lox::Token<'s>, &'s str>;
+pub type ParseError<'s> = lalrpop_util::ParseError<usize, lox::Token<'s>, &'s str>;
 
 #[derive(Debug, PartialEq)]
 pub enum Error<'s> {
-    Parse(ParseError<'s>),
+    /// Syntax errors in the source with id `file`, in the order they were
+    /// found. There's always at least one.
+    Parse {
+        file: ast::FileId,
+        errors: Vec<ParseError<'s>>,
+    },
     Runtime(RuntimeError),
     Assert { location: ast::Location },
     Return(Value),
+    Exit(i32),
+}
+
+// Exit codes, loosely following sysexits.h.
+pub const EXIT_PARSE_ERROR: i32 = 65;
+pub const EXIT_RUNTIME_ERROR: i32 = 70;
+pub const EXIT_ASSERTION_FAILED: i32 = 71;
+pub const EXIT_IO_ERROR: i32 = 74;
+
+impl<'s> Error<'s> {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Parse { .. } => EXIT_PARSE_ERROR,
+            Error::Runtime(RuntimeError::Syntax { .. }) => EXIT_PARSE_ERROR,
+            Error::Runtime(RuntimeError::ReturnOutsideFunction { .. }) => EXIT_PARSE_ERROR,
+            Error::Runtime(_) => EXIT_RUNTIME_ERROR,
+            Error::Assert { .. } => EXIT_ASSERTION_FAILED,
+            Error::Return(_) => EXIT_RUNTIME_ERROR,
+            Error::Exit(code) => *code,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -25,40 +55,154 @@ pub enum RuntimeError {
         name: String,
         location: ast::Location,
     },
+    /// The identifier is a native which needs a capability that's disabled;
+    /// or, if `name` is "import", importing is disabled.
+    CapabilityDisabled {
+        name: String,
+        capability: Capability,
+        location: ast::Location,
+    },
     NotCallable {
         location: ast::Location,
     },
     ArityMismatch {
-        expected: usize,
+        expected: Arity,
         actual: usize,
         location: ast::Location,
     },
+    IndexOutOfRange {
+        index: f64,
+        length: usize,
+        location: ast::Location,
+    },
+    /// A native was given an argument it can't use, such as a malformed
+    /// format string.
+    InvalidArgument {
+        message: String,
+        location: ast::Location,
+    },
+    Io {
+        message: String,
+        location: ast::Location,
+    },
+    StackOverflow {
+        depth: usize,
+        location: ast::Location,
+    },
+    FuelExhausted {
+        fuel: u64,
+        location: ast::Location,
+    },
+    Timeout {
+        location: ast::Location,
+    },
+    Interrupted {
+        location: ast::Location,
+    },
+    OutOfMemory {
+        limit: usize,
+        location: ast::Location,
+    },
+    /// A `return` outside any function. It's found before the program runs.
+    ReturnOutsideFunction { location: ast::Location },
+    /// An imported file has syntax errors.
+    Syntax { errors: Vec<SyntaxError> },
+    /// Importing a file would import the file doing the importing. `cycle`
+    /// lists the files involved, starting and ending with the same one;
+    /// `imports` are the imports that led from each to the next, before the
+    /// one at `location`.
+    ImportCycle {
+        cycle: Vec<String>,
+        imports: Vec<ast::Location>,
+        location: ast::Location,
+    },
+}
+
+/// Errors raised by native functions. These don't know where they were called
+/// from, so `do_call` turns them into located errors.
+#[derive(Debug, PartialEq)]
+pub enum NativeError {
+    /// The argument at this position has the wrong type.
+    TypeMismatch { index: usize },
+    /// The argument at position `argument` is an index, which is out of range.
+    IndexOutOfRange {
+        argument: usize,
+        index: f64,
+        length: usize,
+    },
+    /// The argument at this position has the right type, but is unusable
+    /// for the reason given.
+    InvalidArgument { index: usize, message: String },
+    /// Reading or writing a file failed.
+    Io { message: String },
+    /// The script asked to exit with this code.
+    Exit(i32),
 }
 
-pub fn report_error(path: &str, source: &str, simple_errors: bool, e: Error) {
-    let mut files = SimpleFiles::new();
-    let file_id = files.add(path, source);
+/// What's wrong with some syntax, and where, without reference to the
+/// source it's in.
+#[derive(Debug, PartialEq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub notes: Vec<String>,
+    pub location: Option<ast::Location>,
+}
+
+impl SyntaxError {
+    fn diagnostic(self) -> Diagnostic<ast::FileId> {
+        let labels = self.location.map(|l| Label::primary(l.file, l));
+        Diagnostic::error()
+            .with_message(self.message)
+            .with_notes(self.notes)
+            .with_labels(labels.into_iter().collect())
+    }
+}
+
+/// Reports an error, with labels in whichever of `sources` it happened in
+/// (usually `Interpreter::sources`). Syntax errors are reported one by one.
+pub fn report_error(sources: &SourceMap, simple_errors: bool, e: Error) {
+    for diagnostic in diagnostics(e) {
+        emit(sources, simple_errors, &diagnostic);
+    }
+}
+
+/// The diagnostics that `report_error` would print for `e`: one for each
+/// syntax error, none for `Exit`, or one for anything else.
+pub fn diagnostics(e: Error) -> Vec<Diagnostic<ast::FileId>> {
     let diagnostic = match e {
-        Error::Parse(ParseError::UnrecognizedToken {
-            token: (start, tok, end),
-            expected,
-        }) => Diagnostic::error()
-            .with_message(format!("unrecognized token '{}'", tok))
-            .with_notes(expected_one_of(expected))
-            .with_labels(vec![Label::primary(file_id, start..end)]),
-        Error::Parse(ParseError::InvalidToken { location: start }) => Diagnostic::error()
-            .with_message("invalid token")
-            .with_labels(vec![Label::primary(file_id, start..start + 1)]),
-        Error::Parse(_) => Diagnostic::error().with_message(format!("{:?}", e)),
+        Error::Parse { file, errors } => {
+            return errors
+                .iter()
+                .map(|error| describe_parse_error(file, error).diagnostic())
+                .collect()
+        }
+        Error::Runtime(RuntimeError::Syntax { errors }) => {
+            return errors.into_iter().map(SyntaxError::diagnostic).collect()
+        }
         Error::Runtime(RuntimeError::IdentifierNotFound { name, location }) => Diagnostic::error()
             .with_message(format!("identifier '{}' not found", name))
-            .with_labels(vec![Label::primary(file_id, location)]),
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::CapabilityDisabled {
+            name,
+            capability,
+            location,
+        }) => Diagnostic::error()
+            .with_message(if name == module::IMPORT {
+                "import is disabled".to_string()
+            } else {
+                format!("identifier '{}' not found", name)
+            })
+            .with_labels(vec![Label::primary(location.file, location)])
+            .with_notes(vec![format!(
+                "'{}' needs the '{}' capability, which is disabled",
+                name, capability
+            )]),
         Error::Runtime(RuntimeError::TypeMismatch { location }) => Diagnostic::error()
             .with_message("type mismatch")
-            .with_labels(vec![Label::primary(file_id, location)]),
+            .with_labels(vec![Label::primary(location.file, location)]),
         Error::Runtime(RuntimeError::NotCallable { location }) => Diagnostic::error()
             .with_message("not callable")
-            .with_labels(vec![Label::primary(file_id, location)]),
+            .with_labels(vec![Label::primary(location.file, location)]),
         Error::Runtime(RuntimeError::ArityMismatch {
             expected,
             actual,
@@ -68,22 +212,125 @@ pub fn report_error(path: &str, source: &str, simple_errors: bool, e: Error) {
                 "arity mismatch: expected {} arguments but got {}",
                 expected, actual
             ))
-            .with_labels(vec![Label::primary(file_id, location)]),
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::IndexOutOfRange {
+            index,
+            length,
+            location,
+        }) => Diagnostic::error()
+            .with_message(format!(
+                "index {} out of range for length {}",
+                index, length
+            ))
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::InvalidArgument { message, location }) => Diagnostic::error()
+            .with_message(format!("invalid argument: {}", message))
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::Io { message, location }) => Diagnostic::error()
+            .with_message(format!("i/o error: {}", message))
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::StackOverflow { depth: 0, location }) => Diagnostic::error()
+            .with_message("stack overflow: too deeply nested")
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::StackOverflow { depth, location }) => Diagnostic::error()
+            .with_message(format!("stack overflow after {} nested calls", depth))
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::FuelExhausted { fuel, location }) => Diagnostic::error()
+            .with_message(format!("out of fuel after {} steps", fuel))
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::Timeout { location }) => Diagnostic::error()
+            .with_message("timed out")
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::Interrupted { location }) => Diagnostic::error()
+            .with_message("interrupted")
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::OutOfMemory { limit, location }) => Diagnostic::error()
+            .with_message(format!("out of memory: more than {} bytes in use", limit))
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::ReturnOutsideFunction { location }) => Diagnostic::error()
+            .with_message("can't return from outside a function")
+            .with_labels(vec![Label::primary(location.file, location)]),
+        Error::Runtime(RuntimeError::ImportCycle {
+            cycle,
+            imports,
+            location,
+        }) => {
+            let mut labels: Vec<_> = imports
+                .into_iter()
+                .map(|l| Label::secondary(l.file, l).with_message("imported here"))
+                .collect();
+            labels.push(Label::primary(location.file, location));
+            Diagnostic::error()
+                .with_message("import cycle")
+                .with_labels(labels)
+                .with_notes(vec![cycle.join(" imports ")])
+        }
         Error::Assert { location } => Diagnostic::error()
             .with_message("assertion failed")
-            .with_labels(vec![Label::primary(file_id, location)]),
-        Error::Return(_) => panic!("using error for return values was a bad idea?"),
+            .with_labels(vec![Label::primary(location.file, location)]),
+        // `parse_program` makes sure this can't escape a function, but just
+        // in case.
+        Error::Return(_) => {
+            Diagnostic::error().with_message("can't return from outside a function")
+        }
+        // Not an error, so there's nothing to report.
+        Error::Exit(_) => return vec![],
+    };
+    vec![diagnostic]
+}
+
+pub fn report_io_error(path: &str, simple_errors: bool, e: &std::io::Error) {
+    let files = SimpleFiles::<&str, &str>::new();
+    let diagnostic = Diagnostic::error().with_message(format!("cannot read '{}': {}", path, e));
+    emit(&files, simple_errors, &diagnostic);
+}
+
+/// What to say about a syntax error in `file`, and where.
+pub(crate) fn describe_parse_error(file: ast::FileId, e: &ParseError) -> SyntaxError {
+    let (message, notes, location) = match e {
+        ParseError::UnrecognizedToken {
+            token: (start, tok, end),
+            expected,
+        } => (
+            format!("unrecognized token '{}'", tok),
+            expected_one_of(expected),
+            Some(ast::location_in(file, *start, *end)),
+        ),
+        ParseError::InvalidToken { location: start } => (
+            "invalid token".to_string(),
+            vec![],
+            Some(ast::location_in(file, *start, start + 1)),
+        ),
+        ParseError::UnrecognizedEof {
+            location: start,
+            expected,
+        } => (
+            "unexpected end of file".to_string(),
+            expected_one_of(expected),
+            Some(ast::location_in(file, *start, *start)),
+        ),
+        _ => (format!("{:?}", e), vec![], None),
     };
+    SyntaxError {
+        message,
+        notes,
+        location,
+    }
+}
 
+fn emit<'f, F>(files: &'f F, simple_errors: bool, diagnostic: &Diagnostic<F::FileId>)
+where
+    F: Files<'f>,
+{
     let writer = StandardStream::stderr(ColorChoice::Auto);
     let mut config = codespan_reporting::term::Config::default();
     if simple_errors {
         config.display_style = codespan_reporting::term::DisplayStyle::Short;
     }
-    let _ = codespan_reporting::term::emit(&mut writer.lock(), &config, &files, &diagnostic);
+    let _ = codespan_reporting::term::emit(&mut writer.lock(), &config, files, diagnostic);
 }
 
-fn expected_one_of(expected: Vec<String>) -> Vec<String> {
+fn expected_one_of(expected: &[String]) -> Vec<String> {
     if expected.len() == 1 {
         vec![format!("expected {}", expected[0])]
     } else {
@@ -0,0 +1,278 @@
+//! `format(fmt, ...)`: replaces each `{}` in `fmt` with the next argument,
+//! rendered as `print` would. A placeholder can also say how to lay the
+//! argument out, as in Rust: `{:[[fill]align][0][width][.precision]}`, where
+//! `align` is `<`, `^` or `>`. `{{` and `}}` are literal braces.
+
+use crate::value::{format_number, Value};
+
+/// Why a format string couldn't be used. `argument` is the position of the
+/// offending argument to `format`, counting the format string as 0.
+#[derive(Debug, PartialEq)]
+pub struct FormatError {
+    pub argument: usize,
+    pub message: String,
+}
+
+impl FormatError {
+    fn new(argument: usize, message: impl Into<String>) -> Self {
+        Self {
+            argument,
+            message: message.into(),
+        }
+    }
+}
+
+/// The largest width or precision a placeholder can ask for; the same limit
+/// as Rust's.
+const MAX_WIDTH: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Centre,
+    Right,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<Align>,
+    zero: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+}
+
+pub fn format(fmt: &str, args: &[Value]) -> Result<String, FormatError> {
+    format_checked(fmt, args, |_| Ok(()))
+}
+
+/// Like `format`, but before the result grows to each new length, passes it
+/// to `check`, which can stop it by returning an error message.
+pub fn format_checked(
+    fmt: &str,
+    args: &[Value],
+    check: impl Fn(usize) -> Result<(), String>,
+) -> Result<String, FormatError> {
+    let mut result = String::new();
+    let mut args = args.iter();
+    let mut used = 0;
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(FormatError::new(0, "unclosed '{' in format string")),
+                    }
+                }
+                let spec = parse_spec(&placeholder)?;
+                let arg = args
+                    .next()
+                    .ok_or_else(|| FormatError::new(0, "not enough arguments for format string"))?;
+                used += 1;
+                // A piece is at most as long as the argument, or the width.
+                let mut piece = String::new();
+                render(&mut piece, arg, &spec);
+                check(result.len() + piece.len())
+                    .map_err(|message| FormatError::new(used, message))?;
+                result.push_str(&piece);
+            }
+            '}' => return Err(FormatError::new(0, "unmatched '}' in format string")),
+            c => result.push(c),
+        }
+    }
+    if args.next().is_some() {
+        return Err(FormatError::new(used + 1, "too many arguments for format string"));
+    }
+    Ok(result)
+}
+
+fn parse_spec(placeholder: &str) -> Result<Spec, FormatError> {
+    let invalid = || FormatError::new(0, format!("invalid placeholder '{{{}}}'", placeholder));
+    let mut spec = Spec::default();
+    if placeholder.is_empty() {
+        return Ok(spec);
+    }
+    let rest = placeholder.strip_prefix(':').ok_or_else(invalid)?;
+    let chars: Vec<char> = rest.chars().collect();
+    let mut i = 0;
+    if chars.len() >= 2 && align(chars[1]).is_some() {
+        spec.fill = Some(chars[0]);
+        spec.align = align(chars[1]);
+        i = 2;
+    } else if !chars.is_empty() && align(chars[0]).is_some() {
+        spec.align = align(chars[0]);
+        i = 1;
+    }
+    if chars.get(i) == Some(&'0') {
+        spec.zero = true;
+        i += 1;
+    }
+    let (width, next) = digits(&chars, i);
+    spec.width = width;
+    i = next;
+    if chars.get(i) == Some(&'.') {
+        let (precision, next) = digits(&chars, i + 1);
+        spec.precision = Some(precision.ok_or_else(invalid)?);
+        i = next;
+    }
+    if i != chars.len() {
+        return Err(invalid());
+    }
+    if spec.width.max(spec.precision).is_some_and(|n| n > MAX_WIDTH) {
+        return Err(FormatError::new(
+            0,
+            format!("width or precision in '{{{}}}' is more than {}", placeholder, MAX_WIDTH),
+        ));
+    }
+    Ok(spec)
+}
+
+fn align(c: char) -> Option<Align> {
+    match c {
+        '<' => Some(Align::Left),
+        '^' => Some(Align::Centre),
+        '>' => Some(Align::Right),
+        _ => None,
+    }
+}
+
+/// Parses the number starting at `chars[i]`, if there is one, returning it
+/// (or `usize::MAX` if it's bigger) and the position after it.
+fn digits(chars: &[char], mut i: usize) -> (Option<usize>, usize) {
+    let start = i;
+    while chars.get(i).is_some_and(char::is_ascii_digit) {
+        i += 1;
+    }
+    if i == start {
+        return (None, i);
+    }
+    let s: String = chars[start..i].iter().collect();
+    (Some(s.parse().unwrap_or(usize::MAX)), i)
+}
+
+fn render(result: &mut String, arg: &Value, spec: &Spec) {
+    let text = match (arg, spec.precision) {
+        (Value::Number(n), Some(precision)) if n.is_finite() => format!("{:.*}", precision, n),
+        (Value::Number(n), _) => format_number(*n),
+        (_, Some(precision)) => arg.to_string().chars().take(precision).collect(),
+        (_, None) => arg.to_string(),
+    };
+    let length = text.chars().count();
+    let width = spec.width.unwrap_or(0);
+    if length >= width {
+        result.push_str(&text);
+        return;
+    }
+    let padding = width - length;
+
+    // Zero-padding goes after the sign, and only applies to numbers.
+    if let (Value::Number(n), true, None) = (arg, spec.zero, spec.align) {
+        if n.is_finite() {
+            let digits = text.strip_prefix('-');
+            if digits.is_some() {
+                result.push('-');
+            }
+            pad(result, '0', padding);
+            result.push_str(digits.unwrap_or(&text));
+            return;
+        }
+    }
+
+    // Numbers go on the right by default, like Rust.
+    let default = match arg {
+        Value::Number(_) => Align::Right,
+        _ => Align::Left,
+    };
+    let (before, after) = match spec.align.unwrap_or(default) {
+        Align::Left => (0, padding),
+        Align::Centre => (padding / 2, padding - padding / 2),
+        Align::Right => (padding, 0),
+    };
+    let fill = spec.fill.unwrap_or(' ');
+    pad(result, fill, before);
+    result.push_str(&text);
+    pad(result, fill, after);
+}
+
+fn pad(result: &mut String, fill: char, count: usize) {
+    for _ in 0..count {
+        result.push(fill);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn format_ok(fmt: &str, args: Vec<Value>) -> String {
+        format(fmt, &args).unwrap()
+    }
+
+    #[test]
+    fn placeholders() {
+        let args = vec![Value::String("x".to_string()), Value::Number(2.0), Value::Nil];
+        assert_eq!(format_ok("{} = {} ({})", args), "x = 2 (<nil>)");
+        assert_eq!(format_ok("{{{}}}", vec![Value::Boolean(true)]), "{true}");
+    }
+
+    #[test]
+    fn width_and_alignment() {
+        let n = || vec![Value::Number(42.0)];
+        let s = || vec![Value::String("ab".to_string())];
+        assert_eq!(format_ok("[{:5}]", n()), "[   42]");
+        assert_eq!(format_ok("[{:5}]", s()), "[ab   ]");
+        assert_eq!(format_ok("[{:<5}]", n()), "[42   ]");
+        assert_eq!(format_ok("[{:^6}]", s()), "[  ab  ]");
+        assert_eq!(format_ok("[{:*>5}]", s()), "[***ab]");
+        assert_eq!(format_ok("[{:05}]", vec![Value::Number(-4.5)]), "[-04.5]");
+        assert_eq!(format_ok("[{:3}]", vec![Value::String("ñandú".to_string())]), "[ñandú]");
+    }
+
+    #[test]
+    fn precision() {
+        assert_eq!(format_ok("{:.2}", vec![Value::Number(std::f64::consts::PI)]), "3.14");
+        assert_eq!(format_ok("{:8.3}", vec![Value::Number(1.0)]), "   1.000");
+        assert_eq!(format_ok("{:.3}", vec![Value::String("abcdef".to_string())]), "abc");
+        assert_eq!(format_ok("{:.1}", vec![Value::Number(f64::NAN)]), "nan");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            format("{} {}", &[Value::Nil]),
+            Err(FormatError::new(0, "not enough arguments for format string"))
+        );
+        assert_eq!(
+            format("{}", &[Value::Nil, Value::Nil]),
+            Err(FormatError::new(2, "too many arguments for format string"))
+        );
+        assert_eq!(format("{", &[]).unwrap_err().argument, 0);
+        assert_eq!(format("}", &[]).unwrap_err().argument, 0);
+        assert_eq!(
+            format("{:x}", &[Value::Nil]),
+            Err(FormatError::new(0, "invalid placeholder '{:x}'"))
+        );
+        assert_eq!(
+            format("{:99999999999}", &[Value::Number(1.0)]),
+            Err(FormatError::new(
+                0,
+                "width or precision in '{:99999999999}' is more than 65535"
+            ))
+        );
+        assert!(format("{:.999999999999}", &[Value::Number(1.0)]).is_err());
+        assert!(format("{:.99999999999999999999999}", &[Value::Nil]).is_err());
+        assert_eq!(format("{:65535}", &[Value::Nil]).unwrap().len(), 65535);
+    }
+}
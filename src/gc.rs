@@ -0,0 +1,468 @@
+//! A cycle collector for environments. Environments, lists and maps are
+//! reference-counted, so most of them are freed as soon as they're no longer
+//! used. But a function stored in the scope that it closes over (which is
+//! every recursive function) makes a cycle, which reference counting never
+//! frees.
+//!
+//! Every scope is registered with the heap. To find the garbage, we use trial
+//! deletion: for each scope, and each list and map reachable from one, we
+//! subtract the references held by the others from its reference count.
+//! Whatever's left over is referenced from outside (the interpreter's
+//! globals, the Rust stack, an embedder), so it's alive, as is anything it
+//! refers to. Everything else is garbage, and is cleared to break the cycles.
+
+use crate::environment::Scope;
+use crate::runtime::Runtime;
+use crate::Value;
+use std::cell::{BorrowError, Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::{Rc, Weak};
+
+/// Collect once at least this many scopes have been created since the last
+/// collection (or more, if more survived the last one).
+const MIN_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    /// How many times the collector has run.
+    pub collections: u64,
+    /// How many scopes it has freed.
+    pub scopes_freed: u64,
+    /// How many lists and maps it has freed.
+    pub values_freed: u64,
+    /// How many scopes were alive after the last collection.
+    pub live_scopes: usize,
+}
+
+/// How many of something there are now, and the most there have been.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Count {
+    pub live: usize,
+    pub peak: usize,
+}
+
+impl Count {
+    fn add(&mut self, n: usize) {
+        self.live += n;
+        self.peak = self.peak.max(self.live);
+    }
+
+    fn remove(&mut self, n: usize) {
+        self.live -= n;
+    }
+}
+
+/// What's in the heap. Values are counted when they're stored in a variable;
+/// the contents of lists and maps aren't counted separately.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeapStats {
+    /// How many scopes have ever been created.
+    pub scopes_allocated: u64,
+    pub scopes: Count,
+    /// Values held in variables, by type.
+    pub values: BTreeMap<&'static str, Count>,
+    /// The bytes in strings held in variables.
+    pub string_bytes: Count,
+    /// Roughly how many bytes are in use; see `Interpreter::memory_usage`.
+    pub memory: Count,
+    pub gc: GcStats,
+}
+
+pub(crate) struct Heap {
+    scopes: RefCell<Vec<Weak<RefCell<Scope>>>>,
+    allocations: Cell<usize>,
+    threshold: Cell<usize>,
+    stress: Cell<bool>,
+    stats: Cell<GcStats>,
+    heap_stats: RefCell<HeapStats>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            scopes: RefCell::new(Vec::new()),
+            allocations: Cell::new(0),
+            threshold: Cell::new(MIN_THRESHOLD),
+            stress: Cell::new(false),
+            stats: Cell::new(GcStats::default()),
+            heap_stats: RefCell::new(HeapStats::default()),
+        }
+    }
+}
+
+impl Heap {
+    pub fn register(&self, scope: &Rc<RefCell<Scope>>) {
+        self.scopes.borrow_mut().push(Rc::downgrade(scope));
+        self.allocations.set(self.allocations.get() + 1);
+        let mut stats = self.heap_stats.borrow_mut();
+        stats.scopes_allocated += 1;
+        stats.scopes.add(1);
+    }
+
+    pub fn scope_dropped(&self) {
+        self.heap_stats.borrow_mut().scopes.remove(1);
+    }
+
+    pub fn value_stored(&self, value: &Value) {
+        let mut stats = self.heap_stats.borrow_mut();
+        stats.values.entry(value.type_name()).or_default().add(1);
+        if let Value::String(s) = value {
+            stats.string_bytes.add(s.len());
+        }
+    }
+
+    pub fn value_released(&self, value: &Value) {
+        let mut stats = self.heap_stats.borrow_mut();
+        stats.values.entry(value.type_name()).or_default().remove(1);
+        if let Value::String(s) = value {
+            stats.string_bytes.remove(s.len());
+        }
+    }
+
+    pub fn allocated(&self, bytes: usize) {
+        self.heap_stats.borrow_mut().memory.add(bytes);
+    }
+
+    pub fn freed(&self, bytes: usize) {
+        self.heap_stats.borrow_mut().memory.remove(bytes);
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.heap_stats.borrow().memory.live
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut stats = self.heap_stats.borrow().clone();
+        stats.gc = self.stats.get();
+        stats
+    }
+
+    /// In stress mode, the collector runs at every opportunity after a scope
+    /// is created, rather than waiting for a lot of them.
+    pub fn set_stress(&self, stress: bool) {
+        self.stress.set(stress);
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats.get()
+    }
+
+    fn should_collect(&self) -> bool {
+        let allocations = self.allocations.get();
+        if self.stress.get() {
+            allocations > 0
+        } else {
+            allocations >= self.threshold.get()
+        }
+    }
+
+    /// The scopes that are still alive, forgetting those that aren't.
+    fn live_scopes(&self) -> Vec<Rc<RefCell<Scope>>> {
+        let mut scopes = self.scopes.borrow_mut();
+        let live: Vec<Rc<RefCell<Scope>>> = scopes.iter().filter_map(Weak::upgrade).collect();
+        *scopes = live.iter().map(Rc::downgrade).collect();
+        live
+    }
+}
+
+/// Collects garbage if enough scopes have been created since last time. This
+/// must only be called when no scope, list or map is borrowed.
+pub(crate) fn maybe_collect(runtime: &Runtime) {
+    if runtime.heap().should_collect() {
+        collect(runtime);
+    }
+}
+
+enum Node {
+    Scope(Rc<RefCell<Scope>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<BTreeMap<String, Value>>>),
+}
+
+/// A reference from one node to another.
+enum Edge<'a> {
+    Scope(&'a Rc<RefCell<Scope>>),
+    List(&'a Rc<RefCell<Vec<Value>>>),
+    Map(&'a Rc<RefCell<BTreeMap<String, Value>>>),
+}
+
+fn id<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const u8 as usize
+}
+
+impl Edge<'_> {
+    fn id(&self) -> usize {
+        match self {
+            Edge::Scope(rc) => id(rc),
+            Edge::List(rc) => id(rc),
+            Edge::Map(rc) => id(rc),
+        }
+    }
+
+    fn to_node(&self) -> Node {
+        match self {
+            Edge::Scope(rc) => Node::Scope(Rc::clone(rc)),
+            Edge::List(rc) => Node::List(Rc::clone(rc)),
+            Edge::Map(rc) => Node::Map(Rc::clone(rc)),
+        }
+    }
+}
+
+fn value_edges<'a>(value: &'a Value, f: &mut dyn FnMut(Edge<'a>)) {
+    match value {
+        Value::LoxFunction { closure, .. } => f(Edge::Scope(closure.scope())),
+        Value::Module { globals, .. } => f(Edge::Scope(globals.scope())),
+        Value::List(values) => f(Edge::List(values)),
+        Value::Map(entries) => f(Edge::Map(entries)),
+        _ => {}
+    }
+}
+
+impl Node {
+    fn id(&self) -> usize {
+        match self {
+            Node::Scope(rc) => id(rc),
+            Node::List(rc) => id(rc),
+            Node::Map(rc) => id(rc),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Scope(rc) => Rc::strong_count(rc),
+            Node::List(rc) => Rc::strong_count(rc),
+            Node::Map(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    /// Calls `f` for each reference this node holds to another.
+    fn visit(&self, f: &mut dyn FnMut(Edge)) -> Result<(), BorrowError> {
+        match self {
+            Node::Scope(rc) => {
+                let scope = rc.try_borrow()?;
+                if let Some(enclosing) = scope.enclosing() {
+                    f(Edge::Scope(enclosing.scope()));
+                }
+                for value in scope.values() {
+                    value_edges(value, f);
+                }
+            }
+            Node::List(rc) => {
+                for value in rc.try_borrow()?.iter() {
+                    value_edges(value, f);
+                }
+            }
+            Node::Map(rc) => {
+                for value in rc.try_borrow()?.values() {
+                    value_edges(value, f);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes everything out of this node, breaking any cycles through it.
+    fn clear(&self) -> Box<dyn std::any::Any> {
+        match self {
+            Node::Scope(rc) => Box::new(rc.borrow_mut().clear()),
+            Node::List(rc) => Box::new(std::mem::take(&mut *rc.borrow_mut())),
+            Node::Map(rc) => Box::new(std::mem::take(&mut *rc.borrow_mut())),
+        }
+    }
+}
+
+/// Frees every environment (and list and map) that's only reachable from
+/// others that are garbage. This must only be called when no scope, list or
+/// map is borrowed; if one is, it does nothing.
+pub(crate) fn collect(runtime: &Runtime) {
+    let heap = runtime.heap();
+    heap.allocations.set(0);
+
+    // Find every node: the scopes, and the lists and maps they refer to.
+    let mut nodes: Vec<Node> = heap.live_scopes().into_iter().map(Node::Scope).collect();
+    let mut index: HashMap<usize, usize> =
+        nodes.iter().enumerate().map(|(i, n)| (n.id(), i)).collect();
+    let mut i = 0;
+    while i < nodes.len() {
+        let mut found = Vec::new();
+        let visited = nodes[i].visit(&mut |edge| {
+            if !index.contains_key(&edge.id()) {
+                found.push(edge.to_node());
+            }
+        });
+        if visited.is_err() {
+            return;
+        }
+        for node in found {
+            if let std::collections::hash_map::Entry::Vacant(e) = index.entry(node.id()) {
+                e.insert(nodes.len());
+                nodes.push(node);
+            }
+        }
+        i += 1;
+    }
+
+    // Subtract the references the nodes hold to each other, and the one
+    // we're holding, from their reference counts.
+    let mut external: Vec<usize> = nodes.iter().map(|n| n.strong_count() - 1).collect();
+    for node in &nodes {
+        let _ = node.visit(&mut |edge| {
+            if let Some(&j) = index.get(&edge.id()) {
+                external[j] -= 1;
+            }
+        });
+    }
+
+    // Anything referenced from outside is alive, as is everything it refers to.
+    let mut alive = vec![false; nodes.len()];
+    let mut pending: Vec<usize> = (0..nodes.len()).filter(|&i| external[i] > 0).collect();
+    while let Some(i) = pending.pop() {
+        if alive[i] {
+            continue;
+        }
+        alive[i] = true;
+        let _ = nodes[i].visit(&mut |edge| {
+            if let Some(&j) = index.get(&edge.id()) {
+                if !alive[j] {
+                    pending.push(j);
+                }
+            }
+        });
+    }
+
+    let mut stats = heap.stats.get();
+    let mut garbage = Vec::new();
+    for (node, _) in nodes.iter().zip(&alive).filter(|(_, alive)| !**alive) {
+        match node {
+            Node::Scope(_) => stats.scopes_freed += 1,
+            _ => stats.values_freed += 1,
+        }
+        garbage.push(node.clear());
+    }
+    stats.collections += 1;
+    stats.live_scopes = nodes
+        .iter()
+        .zip(&alive)
+        .filter(|(n, alive)| **alive && matches!(n, Node::Scope(_)))
+        .count();
+    heap.stats.set(stats);
+    heap.threshold.set(MIN_THRESHOLD.max(stats.live_scopes));
+
+    // Dropping the contents, and then our references, frees the garbage.
+    drop(garbage);
+    drop(nodes);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Interpreter;
+    use crate::Value;
+
+    // `inner` is stored in the scope it closes over, so each call to `make`
+    // leaves a cycle behind.
+    const MAKE_CYCLES: &str = "
+        fun make() {
+            fun inner() { return inner; }
+            return 1;
+        }
+        var i = 0;
+        while (i < 10) { make(); i = i + 1; }";
+
+    #[test]
+    fn collects_closure_cycles() {
+        let mut interpreter = Interpreter::new();
+        interpreter.run_source("fun make() { fun inner() { return inner; } return 1; }").unwrap();
+        interpreter.collect_garbage();
+        let before = interpreter.memory_usage();
+        interpreter.run_source(MAKE_CYCLES).unwrap();
+        assert!(interpreter.memory_usage() > before);
+
+        interpreter.collect_garbage();
+        let stats = interpreter.gc_stats();
+        assert!(stats.scopes_freed >= 10);
+        // Everything but the new variable 'i' has been given back.
+        let i = std::mem::size_of::<String>() + 1 + Value::Nil.heap_size();
+        assert_eq!(interpreter.memory_usage(), before + i);
+    }
+
+    #[test]
+    fn keeps_reachable_closures() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .run_source(
+                "fun counter() {
+                    var n = 0;
+                    fun increment() { n = n + 1; return n; }
+                    return increment;
+                }
+                var c = counter();
+                var cs = [counter()];",
+            )
+            .unwrap();
+        interpreter.collect_garbage();
+        assert_eq!(interpreter.eval_expr("c() + c()"), Ok(Value::Number(3.0)));
+        assert_eq!(interpreter.eval_expr("cs[0]()"), Ok(Value::Number(1.0)));
+        interpreter.run_source("c = nil; cs = nil;").unwrap();
+        interpreter.collect_garbage();
+        // Each call has a scope for its parameters, and one for its body.
+        assert_eq!(interpreter.gc_stats().scopes_freed, 4);
+    }
+
+    #[test]
+    fn keeps_values_held_by_embedder() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .run_source("fun make() { var x = 42; fun get() { return x; } return get; } var f = make();")
+            .unwrap();
+        let f = interpreter.get_global("f").unwrap();
+        interpreter.run_source("f = nil;").unwrap();
+        interpreter.collect_garbage();
+        assert_eq!(interpreter.gc_stats().scopes_freed, 0);
+        interpreter.set_global("g", f);
+        assert_eq!(interpreter.eval_expr("g()"), Ok(Value::Number(42.0)));
+    }
+
+    #[test]
+    fn stress_mode_collects_while_running() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_gc_stress(true);
+        interpreter.run_source(MAKE_CYCLES).unwrap();
+        let stats = interpreter.gc_stats();
+        assert!(stats.collections > 10);
+        assert!(stats.scopes_freed >= 10);
+        assert_eq!(interpreter.eval_expr("make()"), Ok(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn dropping_the_interpreter_frees_everything() {
+        let interpreter = Interpreter::new();
+        let runtime = interpreter.globals().runtime().clone();
+        let mut interpreter = interpreter;
+        interpreter.run_source(MAKE_CYCLES).unwrap();
+        drop(interpreter);
+        assert_eq!(runtime.memory_usage(), 0);
+    }
+
+    #[test]
+    fn heap_stats_count_values_by_type() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .run_source("var a = \"abc\"; var b = \"de\"; var n = 1; a = nil;")
+            .unwrap();
+        let stats = interpreter.heap_stats();
+        assert_eq!(stats.values["string"], super::Count { live: 1, peak: 2 });
+        // PI and E are numbers too.
+        assert_eq!(stats.values["number"], super::Count { live: 3, peak: 3 });
+        assert_eq!(stats.values["nil"], super::Count { live: 1, peak: 1 });
+        assert_eq!(stats.string_bytes, super::Count { live: 2, peak: 5 });
+        assert_eq!(stats.memory.live, interpreter.memory_usage());
+        assert!(stats.memory.peak >= stats.memory.live);
+
+        interpreter.run_source("fun f() { var x = 1; } f(); f();").unwrap();
+        let stats = interpreter.heap_stats();
+        // The natives have a scope of their own, enclosing the globals.
+        assert_eq!(stats.scopes.live, 2);
+        assert_eq!(stats.scopes.peak, 4);
+        assert_eq!(stats.scopes_allocated, 6);
+    }
+}
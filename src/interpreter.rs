@@ -1,22 +1,351 @@
 use crate::lox;
+use crate::bindings;
+use crate::capabilities::Capabilities;
+use crate::convert::IntoNative;
 use crate::environment::Environment;
+use crate::gc::{self, GcStats, HeapStats};
+use crate::module;
 use crate::error::*;
-use crate::value::Value;
+use crate::runtime::{Clock, Input, InterruptHandle, Limits, Output};
+use crate::value::{Arity, Value};
 use crate::ast;
 use crate::ast::Locatable;
+use crate::source_map::SourceMap;
+use lalrpop_util::ErrorRecovery;
+use std::cell::Ref;
+use std::path::Path;
+use std::rc::Rc;
 
-pub fn interpret_source<'s>(source: &'s str, environment: &mut Environment) -> Result<(), Error<'s>> {
-    let parser = lox::ProgramParser::new();
-    let program = parser.parse(source).map_err(|e| Error::Parse(e))?;
+/// A Lox interpreter, holding the global environment. Definitions made by one
+/// call to `run_source` are visible to the next.
+///
+/// The natives are defined in an environment enclosing the globals, which is
+/// shared with any modules that scripts import.
+pub struct Interpreter {
+    globals: Environment,
+    /// The last source run, if it didn't define any functions. Nothing refers
+    /// to it once its errors have been reported, so it's removed when the
+    /// next source is added.
+    transient: Option<ast::FileId>,
+}
+
+impl Interpreter {
+    /// Creates an interpreter with the standard native functions defined.
+    pub fn new() -> Self {
+        Self::with_args(&[])
+    }
+
+    /// Creates an interpreter with the standard native functions defined;
+    /// `args` are available to scripts from `args()`.
+    pub fn with_args(args: &[String]) -> Self {
+        Self::with_capabilities(args, &Capabilities::all())
+    }
+
+    /// Creates an interpreter with only those native functions allowed by
+    /// `capabilities` defined.
+    pub fn with_capabilities(args: &[String], capabilities: &Capabilities) -> Self {
+        let mut builtins = Environment::new();
+        bindings::register_globals(&mut builtins, args, capabilities);
+        let globals = Environment::with_enclosing(&builtins);
+        Self {
+            globals,
+            transient: None,
+        }
+    }
+
+    /// Runs a program. In diagnostics, it's called `<source>`.
+    pub fn run_source<'s>(&mut self, source: &'s str) -> Result<(), Error<'s>> {
+        self.run_named("<source>", source)
+    }
+
+    /// Runs a program, which diagnostics call `name`.
+    pub fn run_named<'s>(&mut self, name: &str, source: &'s str) -> Result<(), Error<'s>> {
+        let file = self.add_source(name, source, None);
+        self.run_file(file, source)
+    }
+
+    /// Runs a program read from `path`. Files it imports are found relative
+    /// to it.
+    pub fn run_script<'s>(&mut self, path: &Path, source: &'s str) -> Result<(), Error<'s>> {
+        let file = self.add_source(&path.display().to_string(), source, Some(path));
+        self.run_file(file, source)
+    }
+
+    fn run_file<'s>(&mut self, file: ast::FileId, source: &'s str) -> Result<(), Error<'s>> {
+        let program = parse_program(file, source)?;
+        if ast::defines_functions(&program) {
+            self.transient = None;
+        }
+        self.globals.runtime().start();
+        interpret_statements(&program, &mut self.globals)
+    }
+
+    /// Evaluates a single expression, such as `1 + 2`, and returns its value.
+    /// In diagnostics, it's called `<source>`.
+    pub fn eval_expr<'s>(&mut self, source: &'s str) -> Result<Value, Error<'s>> {
+        self.eval_named("<source>", source)
+    }
+
+    /// Evaluates a single expression, which diagnostics call `name`.
+    pub fn eval_named<'s>(&mut self, name: &str, source: &'s str) -> Result<Value, Error<'s>> {
+        let file = self.add_source(name, source, None);
+        let expr = parse_expr(file, source)?;
+        self.eval_parsed(&expr)
+    }
+
+    /// Evaluates `source` if it's a single expression, returning its value,
+    /// and otherwise runs it as a program, as the REPL does.
+    pub fn eval_or_run<'s>(
+        &mut self,
+        name: &str,
+        source: &'s str,
+    ) -> Result<Option<Value>, Error<'s>> {
+        let file = self.add_source(name, source, None);
+        match parse_expr(file, source) {
+            Ok(expr) => self.eval_parsed(&expr).map(Some),
+            Err(_) => self.run_file(file, source).map(|()| None),
+        }
+    }
+
+    fn eval_parsed<'s>(&mut self, expr: &ast::Expr) -> Result<Value, Error<'s>> {
+        if ast::expr_defines_functions(expr) {
+            self.transient = None;
+        }
+        self.globals.runtime().start();
+        evaluate(expr, &mut self.globals)
+    }
+
+    /// Everything run so far, including imported files, for
+    /// `error::report_error`.
+    pub fn sources(&self) -> Ref<'_, SourceMap> {
+        self.globals.runtime().sources().borrow()
+    }
+
+    /// Adds a source, which is transient until it turns out to define
+    /// functions, and removes the previous one if that's transient.
+    fn add_source(&mut self, name: &str, source: &str, path: Option<&Path>) -> ast::FileId {
+        let mut sources = self.globals.runtime().sources().borrow_mut();
+        if let Some(file) = self.transient.take() {
+            sources.remove(file);
+        }
+        let file = sources.add(name, source, path);
+        self.transient = Some(file);
+        file
+    }
+
+    /// Calls the global function `name`. There's no source code for the call,
+    /// so errors in the call itself point at a source called `<call name>`,
+    /// which just holds the name.
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Error<'static>> {
+        let file = self.add_source(&format!("<call {}>", name), name, None);
+        let location = ast::location_in(file, 0, name.len());
+        let callable = self.globals.get(name).ok_or_else(|| {
+            Error::Runtime(RuntimeError::IdentifierNotFound {
+                name: name.to_string(),
+                location,
+            })
+        })?;
+        self.globals.runtime().start();
+        call_value(callable, args, location, &[])
+    }
+
+    /// Defines a native function, taking exactly `arity` arguments, which
+    /// are passed to `fun` unconverted.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        fun: impl Fn(Vec<Value>) -> Result<Value, NativeError> + 'static,
+    ) {
+        bindings::define_native(&mut self.globals.root(), name, arity, fun);
+    }
+
+    /// Defines a native function taking any number of arguments, as long as
+    /// there are at least `min_arity`.
+    pub fn define_variadic_native(
+        &mut self,
+        name: &str,
+        min_arity: usize,
+        fun: impl Fn(Vec<Value>) -> Result<Value, NativeError> + 'static,
+    ) {
+        bindings::define_variadic_native(&mut self.globals.root(), name, min_arity, fun);
+    }
+
+    /// Defines a native function taking as many arguments as `arity` allows.
+    pub fn define_native_with_arity(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        fun: impl Fn(Vec<Value>) -> Result<Value, NativeError> + 'static,
+    ) {
+        bindings::define_native_with_arity(&mut self.globals.root(), name, arity, fun);
+    }
+
+    /// Registers a Rust function as a native, converting its arguments from,
+    /// and its result to, `Value`. See `convert::IntoNative`.
+    pub fn register_fn<Args>(&mut self, name: &str, fun: impl IntoNative<Args>) {
+        bindings::register_fn(&mut self.globals.root(), name, fun);
+    }
+
+    /// Sets where `print` writes to. By default, it's stdout.
+    pub fn set_output(&mut self, output: Output) {
+        self.globals.runtime().set_output(output);
+    }
+
+    /// Sets where `read_line`, `read_all` and `input` read from. By default,
+    /// it's stdin.
+    pub fn set_input(&mut self, input: Input) {
+        self.globals.runtime().set_input(input);
+    }
+
+    /// Limits how much work scripts may do. By default, only the call depth
+    /// is limited.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.globals.runtime().set_limits(limits);
+    }
+
+    /// Roughly how many bytes are taken up by variables and their values.
+    /// This is what `Limits::max_memory` limits.
+    pub fn memory_usage(&self) -> usize {
+        self.globals.runtime().memory_usage()
+    }
+
+    /// Sets where `clock()` gets the time from.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.globals.runtime().set_clock(clock);
+    }
+
+    /// Seeds `random()`, so that it returns the same numbers every time.
+    /// That makes it predictable, so it's defined even if the `time`
+    /// capability isn't enabled.
+    pub fn seed_random(&mut self, seed: u64) {
+        let runtime = self.globals.runtime().clone();
+        runtime.seed_random(seed);
+        if runtime.disabled_capability(bindings::RANDOM).is_some() {
+            runtime.enable(bindings::RANDOM);
+            bindings::define_random(&mut self.globals.root());
+        }
+    }
+
+    /// Frees environments that are only kept alive by reference cycles. This
+    /// happens automatically, every so often.
+    pub fn collect_garbage(&mut self) {
+        gc::collect(self.globals.runtime());
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.globals.runtime().gc_stats()
+    }
+
+    /// What's in the heap now, and the most there's been.
+    pub fn heap_stats(&self) -> HeapStats {
+        self.globals.runtime().heap_stats()
+    }
+
+    /// Collects garbage at every opportunity, to help find bugs in the
+    /// collector.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.globals.runtime().heap().set_stress(stress);
+    }
+
+    /// Returns a handle that can be used to stop a running script from
+    /// another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.globals.runtime().interrupt_handle()
+    }
+
+    /// Returns the text printed since the last call, if the output was set to
+    /// `Output::Buffer`.
+    pub fn take_output(&mut self) -> String {
+        self.globals.runtime().take_output()
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name)
+    }
+
+    /// Defines (or redefines) a global variable.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) {
+        self.globals.define(name, value.into());
+    }
+
+    /// The global environment. Since environments are shared, changes made
+    /// through this are visible to the interpreter.
+    pub fn globals(&self) -> Environment {
+        self.globals.clone()
+    }
+}
+
+impl Drop for Interpreter {
+    fn drop(&mut self) {
+        // The globals are usually only kept alive by the functions defined in
+        // them, and by us; and modules by the runtime. Let go of them, and
+        // collect the cycles.
+        let runtime = self.globals.runtime().clone();
+        drop(runtime.modules().borrow_mut().unload());
+        drop(std::mem::take(&mut self.globals));
+        gc::collect(&runtime);
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses a program from the source with id `file`. If there are syntax
+/// errors, the parser carries on after each one, so that they can all be
+/// reported. A `return` outside a function is an error too.
+pub fn parse_program<'s>(file: ast::FileId, source: &'s str) -> Result<Vec<ast::Stmt>, Error<'s>> {
+    let mut recovered = Vec::new();
+    let result = lox::ProgramParser::new().parse(file, &mut recovered, source);
+    let program = with_recovered_errors(file, recovered, result)?;
+    match ast::find_top_level_return(&program) {
+        Some(location) => Err(Error::Runtime(RuntimeError::ReturnOutsideFunction { location })),
+        None => Ok(program),
+    }
+}
+
+/// Parses a single expression, like `parse_program`.
+pub fn parse_expr<'s>(file: ast::FileId, source: &'s str) -> Result<ast::Expr, Error<'s>> {
+    let mut recovered = Vec::new();
+    let result = lox::ExprParser::new().parse(file, &mut recovered, source);
+    with_recovered_errors(file, recovered, result)
+}
+
+/// Fails if the parser had to recover from any errors, even if it finished.
+fn with_recovered_errors<'s, T>(
+    file: ast::FileId,
+    recovered: Vec<ErrorRecovery<usize, lox::Token<'s>, &'static str>>,
+    result: Result<T, ParseError<'s>>,
+) -> Result<T, Error<'s>> {
+    let mut errors: Vec<ParseError<'s>> = recovered.into_iter().map(|r| r.error).collect();
+    match result {
+        Ok(parsed) if errors.is_empty() => Ok(parsed),
+        Ok(_) => Err(Error::Parse { file, errors }),
+        Err(error) => {
+            errors.push(error);
+            Err(Error::Parse { file, errors })
+        }
+    }
+}
+
+pub fn interpret_source<'s>(
+    file: ast::FileId,
+    source: &'s str,
+    environment: &mut Environment,
+) -> Result<(), Error<'s>> {
+    let program = parse_program(file, source)?;
     interpret_statements(&program, environment)
 }
 
-fn interpret_statements<'s>(
+pub(crate) fn interpret_statements<'s>(
     statements: &Vec<ast::Stmt>,
     environment: &mut Environment,
 ) -> Result<(), Error<'s>> {
     for s in statements {
-        interpret_statement(&s, environment)?;
+        interpret_statement(s, environment)?;
     }
     Ok(())
 }
@@ -29,14 +358,19 @@ fn interpret_statement<'s>(
     match statement {
         Empty => Ok(()),
         Expr(e) => {
-            evaluate(&e, environment)?;
+            evaluate(e, environment)?;
             Ok(())
         }
         Print(e) => {
-            do_print(evaluate(&e, environment)?);
-            Ok(())
+            let value = evaluate(e, environment)?;
+            do_print(value, environment).map_err(|err| {
+                Error::Runtime(RuntimeError::Io {
+                    message: err.to_string(),
+                    location: e.location(),
+                })
+            })
         }
-        Assert { expr, location } => match evaluate(&expr, environment)? {
+        Assert { expr, location } => match evaluate(expr, environment)? {
             Value::Nil => Err(Error::Assert {
                 location: *location,
             }),
@@ -46,8 +380,8 @@ fn interpret_statement<'s>(
             _ => Ok(()),
         },
         VarDecl { name, init, .. } => {
-            let value = evaluate(&init, environment)?;
-            environment.define(&name, value);
+            let value = evaluate(init, environment)?;
+            environment.define(name, value);
             Ok(())
         }
         FunDecl {
@@ -61,14 +395,23 @@ fn interpret_statement<'s>(
             let closure = environment.clone();
             let callable = Value::LoxFunction {
                 name: name.to_string(),
-                closure: closure,
+                closure,
                 params: params.to_vec(),
                 body: body.clone(),
             };
             environment.define(name, callable);
             Ok(())
         }
-        Block(statements) => {
+        Import {
+            path,
+            name,
+            location,
+        } => module::import(path, name, *location, environment),
+        Block { statements, location } => {
+            environment
+                .runtime()
+                .check_stack(*location)
+                .map_err(Error::Runtime)?;
             // This should create a new environment, so that 'var' is scoped correctly.
             // But: does that break anything because we use Block for a few other things?
             let mut environment = Environment::with_enclosing(environment);
@@ -81,16 +424,20 @@ fn interpret_statement<'s>(
             let value = evaluate(expr, environment)?;
             Err(Error::Return(value))
         }
-        If { cond, then, else_ } => match evaluate(&cond, environment) {
+        If { cond, then, else_ } => match evaluate(cond, environment) {
             Ok(Value::Boolean(true)) => interpret_statement(then, environment),
             Ok(Value::Boolean(false)) => interpret_statement(else_, environment),
             Ok(_) => Err(Error::Runtime(RuntimeError::TypeMismatch { location: cond.location() })),
             Err(e) => Err(e),
         },
         While { cond, body } => loop {
-            match evaluate(&cond, environment) {
+            environment
+                .runtime()
+                .check_interrupt(cond.location())
+                .map_err(Error::Runtime)?;
+            match evaluate(cond, environment) {
                 Ok(Value::Boolean(true)) => {
-                    interpret_statement(&body, environment)?;
+                    interpret_statement(body, environment)?;
                 }
                 Ok(Value::Boolean(false)) => {
                     return Ok(());
@@ -104,18 +451,23 @@ fn interpret_statement<'s>(
     }
 }
 
-pub fn evaluate<'s>(expr: &ast::Expr, environment: &mut Environment) -> Result<Value, Error<'s>> {
+pub(crate) fn evaluate<'s>(expr: &ast::Expr, environment: &mut Environment) -> Result<Value, Error<'s>> {
+    gc::maybe_collect(environment.runtime());
+    environment
+        .runtime()
+        .step(expr.location())
+        .map_err(Error::Runtime)?;
     match expr {
         ast::Expr::Nil { .. } => Ok(Value::Nil),
         ast::Expr::Number { value, .. } => Ok(Value::Number(*value)),
         ast::Expr::Boolean { value, .. } => Ok(Value::Boolean(*value)),
         ast::Expr::String { value, .. } => Ok(Value::String(value.into())),
         ast::Expr::Unary { op, right, .. } => match op {
-            ast::UnaryOp::Invert => match evaluate(&right, environment)? {
+            ast::UnaryOp::Invert => match evaluate(right, environment)? {
                 Value::Boolean(b) => Ok(Value::Boolean(!b)),
                 _ => Err(Error::Runtime(RuntimeError::TypeMismatch { location: right.location() })),
             },
-            ast::UnaryOp::Negate => match evaluate(&right, environment)? {
+            ast::UnaryOp::Negate => match evaluate(right, environment)? {
                 Value::Number(n) => Ok(Value::Number(-n)),
                 _ => Err(Error::Runtime(RuntimeError::TypeMismatch { location: right.location() })),
             },
@@ -123,34 +475,42 @@ pub fn evaluate<'s>(expr: &ast::Expr, environment: &mut Environment) -> Result<V
         ast::Expr::Binary {
             left, op, right, ..
         } => match op {
-            ast::BinaryOp::Add => do_add(&left, &right, environment),
-            ast::BinaryOp::Sub => do_sub(&left, &right, environment),
-            ast::BinaryOp::Mul => do_mul(&left, &right, environment),
-            ast::BinaryOp::Div => do_div(&left, &right, environment),
-            ast::BinaryOp::Mod => do_mod(&left, &right, environment),
-            ast::BinaryOp::Eq => do_eq(&left, &right, environment),
-            ast::BinaryOp::Ne => do_ne(&left, &right, environment),
-            ast::BinaryOp::Lt => do_lt(&left, &right, environment),
-            ast::BinaryOp::Le => do_le(&left, &right, environment),
-            ast::BinaryOp::Gt => do_gt(&left, &right, environment),
-            ast::BinaryOp::Ge => do_ge(&left, &right, environment),
+            ast::BinaryOp::Add => do_add(left, right, environment),
+            ast::BinaryOp::Sub => do_sub(left, right, environment),
+            ast::BinaryOp::Mul => do_mul(left, right, environment),
+            ast::BinaryOp::Div => do_div(left, right, environment),
+            ast::BinaryOp::Mod => do_mod(left, right, environment),
+            ast::BinaryOp::Eq => do_eq(left, right, environment),
+            ast::BinaryOp::Ne => do_ne(left, right, environment),
+            ast::BinaryOp::Lt => do_lt(left, right, environment),
+            ast::BinaryOp::Le => do_le(left, right, environment),
+            ast::BinaryOp::Gt => do_gt(left, right, environment),
+            ast::BinaryOp::Ge => do_ge(left, right, environment),
         },
-        ast::Expr::Var { name, .. } => environment.get(&name).ok_or_else(|| {
-            Error::Runtime(RuntimeError::IdentifierNotFound {
-                name: name.into(),
-                location: expr.location(),
+        ast::Expr::Var { name, .. } => environment.get(name).ok_or_else(|| {
+            let location = expr.location();
+            Error::Runtime(match environment.runtime().disabled_capability(name) {
+                Some(capability) => RuntimeError::CapabilityDisabled {
+                    name: name.into(),
+                    capability,
+                    location,
+                },
+                None => RuntimeError::IdentifierNotFound {
+                    name: name.into(),
+                    location,
+                },
             })
         }),
         ast::Expr::Assignment { name, rhs, .. } => {
-            let value = evaluate(&rhs, environment)?;
-            environment.assign(&name, value).or(Err(Error::Runtime(
+            let value = evaluate(rhs, environment)?;
+            environment.assign(name, value).or(Err(Error::Runtime(
                 RuntimeError::IdentifierNotFound {
                     name: name.into(),
                     location: expr.location(),
                 },
             )))
         }
-        ast::Expr::Call { callee, args, .. } => do_call(&callee, args, environment),
+        ast::Expr::Call { callee, args, .. } => do_call(callee, args, environment),
         ast::Expr::Fun {
             params,
             body,
@@ -160,24 +520,44 @@ pub fn evaluate<'s>(expr: &ast::Expr, environment: &mut Environment) -> Result<V
             let closure = environment.clone();
             Ok(Value::LoxFunction {
                 name: format!("<anon@{}>", location.start),
-                closure: closure,
+                closure,
                 params: params.to_vec(),
                 body: body.clone(),
             })
         }
+        ast::Expr::List { elements, .. } => {
+            let mut values = Vec::with_capacity(elements.len());
+            for e in elements {
+                let value = evaluate(e, environment)?;
+                environment
+                    .runtime()
+                    .check_nesting(&value, e.location())
+                    .map_err(Error::Runtime)?;
+                values.push(value);
+            }
+            Ok(Value::list(values))
+        }
+        ast::Expr::Index { object, index, .. } => do_index(object, index, environment),
+        ast::Expr::Get {
+            object,
+            name,
+            location,
+        } => match evaluate(object, environment)? {
+            Value::Module { globals, .. } => globals.get_local(name).ok_or_else(|| {
+                Error::Runtime(RuntimeError::IdentifierNotFound {
+                    name: name.to_string(),
+                    location: *location,
+                })
+            }),
+            _ => Err(Error::Runtime(RuntimeError::TypeMismatch {
+                location: object.location(),
+            })),
+        },
     }
 }
 
-fn do_print(e: Value) {
-    use Value::*;
-    match e {
-        Nil => println!("<nil>"),
-        Number(n) => println!("{}", n),
-        Boolean(b) => println!("{}", b),
-        String(s) => println!("{}", s),
-        NativeFunction { name, .. } => println!("<fun {} (native)>", name),
-        LoxFunction { name, .. } => println!("<fun {} (lox)>", name),
-    }
+fn do_print(e: Value, environment: &Environment) -> std::io::Result<()> {
+    environment.runtime().print(&e.to_string())
 }
 
 fn do_add<'s>(lhs: &ast::Expr, rhs: &ast::Expr, environment: &mut Environment) -> Result<Value, Error<'s>> {
@@ -185,7 +565,15 @@ fn do_add<'s>(lhs: &ast::Expr, rhs: &ast::Expr, environment: &mut Environment) -
     let rv = evaluate(rhs, environment)?;
     match (lv, rv) {
         (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
-        (Value::String(l), Value::String(r)) => Ok(Value::String(l + &r)),
+        (Value::String(l), Value::String(r)) => {
+            // The result isn't accounted for until it's stored somewhere, but
+            // don't let one enormous string through.
+            environment
+                .runtime()
+                .check_memory(l.len() + r.len(), rhs.location())
+                .map_err(Error::Runtime)?;
+            Ok(Value::String(l + &r))
+        }
         _ => Err(Error::Runtime(RuntimeError::TypeMismatch { location: rhs.location() })),
     }
 }
@@ -229,26 +617,28 @@ fn do_mod<'s>(lhs: &ast::Expr, rhs: &ast::Expr, environment: &mut Environment) -
 fn do_eq<'s>(lhs: &ast::Expr, rhs: &ast::Expr, environment: &mut Environment) -> Result<Value, Error<'s>> {
     let lv = evaluate(lhs, environment)?;
     let rv = evaluate(rhs, environment)?;
-    match (lv, rv) {
-        (Value::Number(l), Value::Number(r)) => Ok(Value::Boolean(l == r)),
-        (Value::String(l), Value::String(r)) => Ok(Value::Boolean(l == r)),
-        (Value::Boolean(l), Value::Boolean(r)) => Ok(Value::Boolean(l == r)),
-        (Value::Nil, Value::Nil) => Ok(Value::Boolean(true)),
-        // Types don't match => false
-        _ => Ok(Value::Boolean(false)),
-    }
+    Ok(Value::Boolean(equal(&lv, &rv)))
 }
 
 fn do_ne<'s>(lhs: &ast::Expr, rhs: &ast::Expr, environment: &mut Environment) -> Result<Value, Error<'s>> {
     let lv = evaluate(lhs, environment)?;
     let rv = evaluate(rhs, environment)?;
+    Ok(Value::Boolean(!equal(&lv, &rv)))
+}
+
+/// Lox's `==`. Lists, maps and modules are only equal to themselves, not to
+/// others with the same contents.
+fn equal(lv: &Value, rv: &Value) -> bool {
     match (lv, rv) {
-        (Value::Number(l), Value::Number(r)) => Ok(Value::Boolean(l != r)),
-        (Value::String(l), Value::String(r)) => Ok(Value::Boolean(l != r)),
-        (Value::Boolean(l), Value::Boolean(r)) => Ok(Value::Boolean(l != r)),
-        (Value::Nil, Value::Nil) => Ok(Value::Boolean(false)),
-        // Types don't match => true
-        _ => Ok(Value::Boolean(true)),
+        (Value::Number(l), Value::Number(r)) => l == r,
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Boolean(l), Value::Boolean(r)) => l == r,
+        (Value::Nil, Value::Nil) => true,
+        (Value::List(l), Value::List(r)) => Rc::ptr_eq(l, r),
+        (Value::Map(l), Value::Map(r)) => Rc::ptr_eq(l, r),
+        (Value::Module { globals: l, .. }, Value::Module { globals: r, .. }) => l.same(r),
+        // Types don't match => false
+        _ => false,
     }
 }
 
@@ -288,6 +678,39 @@ fn do_ge<'s>(lhs: &ast::Expr, rhs: &ast::Expr, environment: &mut Environment) ->
     }
 }
 
+fn do_index<'s>(
+    object: &ast::Expr,
+    index: &ast::Expr,
+    environment: &mut Environment,
+) -> Result<Value, Error<'s>> {
+    let ov = evaluate(object, environment)?;
+    let iv = evaluate(index, environment)?;
+    match (ov, iv) {
+        (Value::List(values), Value::Number(i)) => {
+            let values = values.borrow();
+            if i.fract() != 0.0 || i < 0.0 || i >= values.len() as f64 {
+                return Err(Error::Runtime(RuntimeError::IndexOutOfRange {
+                    index: i,
+                    length: values.len(),
+                    location: index.location(),
+                }));
+            }
+            Ok(values[i as usize].clone())
+        }
+        (Value::Map(entries), Value::String(key)) => {
+            Ok(entries.borrow().get(&key).cloned().unwrap_or(Value::Nil))
+        }
+        (Value::List(_), _) | (Value::Map(_), _) => {
+            Err(Error::Runtime(RuntimeError::TypeMismatch {
+                location: index.location(),
+            }))
+        }
+        _ => Err(Error::Runtime(RuntimeError::TypeMismatch {
+            location: object.location(),
+        })),
+    }
+}
+
 fn do_call<'s>(
     callee: &ast::Expr,
     args: &Vec<ast::Expr>,
@@ -305,17 +728,53 @@ fn do_call<'s>(
     for a in args {
         argv.push(evaluate(a, environment)?);
     }
+    let arg_locations: Vec<ast::Location> = args.iter().map(|a| a.location()).collect();
+    call_value(callable, argv, callee.location(), &arg_locations)
+}
+
+/// Calls `callable` with the given arguments. Errors are reported at
+/// `location`, or at the location of the offending argument, if known.
+pub(crate) fn call_value<'s>(
+    callable: Value,
+    argv: Vec<Value>,
+    location: ast::Location,
+    arg_locations: &[ast::Location],
+) -> Result<Value, Error<'s>> {
     match callable {
         Value::NativeFunction { fun, arity, .. } => {
-            if argv.len() != arity {
+            if !arity.accepts(argv.len()) {
                 return Err(Error::Runtime(RuntimeError::ArityMismatch {
                     expected: arity,
                     actual: argv.len(),
-                    location: callee.location(),
+                    location,
                 }));
             }
-            let r = fun(argv);
-            Ok(r)
+            fun.call(argv).map_err(|e| match e {
+                NativeError::TypeMismatch { index } => {
+                    Error::Runtime(RuntimeError::TypeMismatch {
+                        location: arg_locations.get(index).copied().unwrap_or(location),
+                    })
+                }
+                NativeError::IndexOutOfRange {
+                    argument,
+                    index,
+                    length,
+                } => Error::Runtime(RuntimeError::IndexOutOfRange {
+                    index,
+                    length,
+                    location: arg_locations.get(argument).copied().unwrap_or(location),
+                }),
+                NativeError::InvalidArgument { index, message } => {
+                    Error::Runtime(RuntimeError::InvalidArgument {
+                        message,
+                        location: arg_locations.get(index).copied().unwrap_or(location),
+                    })
+                }
+                NativeError::Io { message } => {
+                    Error::Runtime(RuntimeError::Io { message, location })
+                }
+                NativeError::Exit(code) => Error::Exit(code),
+            })
         }
         Value::LoxFunction {
             closure,
@@ -325,26 +784,25 @@ fn do_call<'s>(
         } => {
             if argv.len() != params.len() {
                 return Err(Error::Runtime(RuntimeError::ArityMismatch {
-                    expected: params.len(),
+                    expected: Arity::Exactly(params.len()),
                     actual: argv.len(),
-                    location: callee.location(),
+                    location,
                 }));
             }
             let mut environment = Environment::with_enclosing(&closure);
             for (p, v) in params.iter().zip(argv.iter()) {
                 environment.define(p, v.clone());
             }
-            match interpret_statement(&body, &mut environment) {
+            let runtime = closure.runtime();
+            runtime.enter_call(location).map_err(Error::Runtime)?;
+            let result = interpret_statement(&body, &mut environment);
+            runtime.leave_call();
+            match result {
                 Ok(()) => Ok(Value::Nil),
                 Err(Error::Return(value)) => Ok(value),
                 Err(e) => Err(e),
             }
         }
-        _ => {
-            let location = callee.location();
-            Err(Error::Runtime(RuntimeError::NotCallable {
-                location: location,
-            }))
-        }
+        _ => Err(Error::Runtime(RuntimeError::NotCallable { location })),
     }
 }
@@ -0,0 +1,519 @@
+//! Converting between JSON text and Lox values. Objects become maps, arrays
+//! become lists, and `null` becomes nil; everything else is what you'd
+//! expect. Functions can't be converted to JSON, and neither can NaN or
+//! infinity.
+
+use crate::value::{format_number, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// How deeply arrays and objects may be nested, in either direction. This
+/// stops a malicious document (or a list that contains itself) from
+/// overflowing the stack.
+const MAX_DEPTH: usize = 512;
+
+/// The most spaces `stringify` will indent by, as in JavaScript.
+pub const MAX_INDENT: usize = 10;
+
+/// Why some JSON couldn't be parsed, and where; lines and columns start at 1,
+/// and columns count characters.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid JSON at line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+/// Why a value couldn't be converted to JSON.
+#[derive(Debug, PartialEq)]
+pub struct StringifyError {
+    pub message: String,
+}
+
+impl fmt::Display for StringifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser {
+        text,
+        position: 0,
+        depth: 0,
+    };
+    parser.skip_whitespace();
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.peek().is_some() {
+        return Err(parser.error("unexpected text after the value"));
+    }
+    Ok(value)
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    /// A byte offset into `text`.
+    position: usize,
+    depth: usize,
+}
+
+impl<'t> Parser<'t> {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        let before = &self.text[..self.position];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        ParseError {
+            message: message.into(),
+            line,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.next();
+                Ok(())
+            }
+            _ => Err(self.unexpected(&format!("'{}'", expected))),
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.peek() {
+            Some(c) => self.error(format!("expected {}, found '{}'", expected, c)),
+            None => self.error(format!("expected {}, found the end of the text", expected)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.next();
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('{') => self.nested(Parser::object),
+            Some('[') => self.nested(Parser::array),
+            Some('"') => self.string().map(Value::String),
+            Some('-' | '0'..='9') => self.number(),
+            Some('t') => self.keyword("true", Value::Boolean(true)),
+            Some('f') => self.keyword("false", Value::Boolean(false)),
+            Some('n') => self.keyword("null", Value::Nil),
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Value, ParseError>,
+    ) -> Result<Value, ParseError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut entries = BTreeMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.next();
+            return Ok(Value::map(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.unexpected("a string"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            self.skip_whitespace();
+            let value = self.value()?;
+            entries.insert(key, value);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => {
+                    self.next();
+                }
+                Some('}') => {
+                    self.next();
+                    return Ok(Value::map(entries));
+                }
+                _ => return Err(self.unexpected("',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.next();
+            return Ok(Value::list(values));
+        }
+        loop {
+            self.skip_whitespace();
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => {
+                    self.next();
+                }
+                Some(']') => {
+                    self.next();
+                    return Ok(Value::list(values));
+                }
+                _ => return Err(self.unexpected("',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.next();
+                    return Ok(s);
+                }
+                Some('\\') => {
+                    self.next();
+                    s.push(self.escape()?);
+                }
+                Some(c) if (c as u32) < 0x20 => {
+                    return Err(self.error("control characters must be escaped in strings"))
+                }
+                Some(c) => {
+                    self.next();
+                    s.push(c);
+                }
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Parses what follows a backslash in a string.
+    fn escape(&mut self) -> Result<char, ParseError> {
+        let c = match self.peek() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                self.next();
+                return self.unicode_escape();
+            }
+            _ => return Err(self.error("invalid escape in string")),
+        };
+        self.next();
+        Ok(c)
+    }
+
+    /// Parses the hex digits of a `\u` escape, and the low half of a
+    /// surrogate pair, if there is one.
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error("invalid unicode escape"));
+        }
+        if !self.text[self.position..].starts_with("\\u") {
+            return Err(self.error("unpaired surrogate in unicode escape"));
+        }
+        self.position += 2;
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(self.error("unpaired surrogate in unicode escape"));
+        }
+        let c = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+        char::from_u32(c).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self.text[self.position..]
+            .get(..4)
+            .filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("expected four hex digits in unicode escape"))?;
+        self.position += 4;
+        Ok(u32::from_str_radix(digits, 16).expect("checked that these are hex digits"))
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.position;
+        if self.peek() == Some('-') {
+            self.next();
+        }
+        match self.peek() {
+            Some('0') => {
+                self.next();
+            }
+            Some('1'..='9') => self.digits(),
+            _ => return Err(self.unexpected("a digit")),
+        }
+        if self.peek() == Some('.') {
+            self.next();
+            self.required_digits()?;
+        }
+        if let Some('e' | 'E') = self.peek() {
+            self.next();
+            if let Some('+' | '-') = self.peek() {
+                self.next();
+            }
+            self.required_digits()?;
+        }
+        let n = self.text[start..self.position]
+            .parse()
+            .expect("a valid JSON number is a valid f64");
+        Ok(Value::Number(n))
+    }
+
+    fn digits(&mut self) {
+        while let Some('0'..='9') = self.peek() {
+            self.next();
+        }
+    }
+
+    fn required_digits(&mut self) -> Result<(), ParseError> {
+        match self.peek() {
+            Some('0'..='9') => {
+                self.digits();
+                Ok(())
+            }
+            _ => Err(self.unexpected("a digit")),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Value) -> Result<Value, ParseError> {
+        if !self.text[self.position..].starts_with(keyword) {
+            return Err(self.unexpected("a value"));
+        }
+        self.position += keyword.len();
+        Ok(value)
+    }
+}
+
+/// Converts `value` to JSON. With an `indent` of more than zero, arrays and
+/// objects are spread over several lines, indented by that many spaces, up to
+/// `MAX_INDENT`.
+pub fn stringify(value: &Value, indent: usize) -> Result<String, StringifyError> {
+    let indent = indent.min(MAX_INDENT);
+    let mut writer = Writer {
+        out: String::new(),
+        indent,
+        depth: 0,
+    };
+    writer.value(value)?;
+    Ok(writer.out)
+}
+
+struct Writer {
+    out: String,
+    indent: usize,
+    depth: usize,
+}
+
+impl Writer {
+    fn value(&mut self, value: &Value) -> Result<(), StringifyError> {
+        match value {
+            Value::Nil => self.out.push_str("null"),
+            Value::Boolean(b) => self.out.push_str(if *b { "true" } else { "false" }),
+            Value::Number(n) if n.is_finite() => self.out.push_str(&format_number(*n)),
+            Value::Number(n) => {
+                return Err(error(format!("cannot convert {} to JSON", format_number(*n))))
+            }
+            Value::String(s) => self.string(s),
+            Value::List(values) => {
+                self.enter(Rc::strong_count(values))?;
+                let values = values.borrow();
+                self.out.push('[');
+                for (i, v) in values.iter().enumerate() {
+                    self.separator(i);
+                    self.value(v)?;
+                }
+                self.close(values.is_empty(), ']');
+            }
+            Value::Map(entries) => {
+                self.enter(Rc::strong_count(entries))?;
+                let entries = entries.borrow();
+                self.out.push('{');
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    self.separator(i);
+                    self.string(k);
+                    self.out.push_str(if self.indent > 0 { ": " } else { ":" });
+                    self.value(v)?;
+                }
+                self.close(entries.is_empty(), '}');
+            }
+            Value::NativeFunction { .. } | Value::LoxFunction { .. } => {
+                return Err(error("cannot convert a function to JSON"))
+            }
+            Value::Module { .. } => return Err(error("cannot convert a module to JSON")),
+        }
+        Ok(())
+    }
+
+    /// Starts an array or object. Only a shared one can contain itself, so
+    /// only they count towards the depth limit.
+    fn enter(&mut self, references: usize) -> Result<(), StringifyError> {
+        if self.depth >= MAX_DEPTH {
+            let message = if references > 1 {
+                "cannot convert a value that contains itself to JSON"
+            } else {
+                "value is too deeply nested to convert to JSON"
+            };
+            return Err(error(message));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn separator(&mut self, i: usize) {
+        if i > 0 {
+            self.out.push(',');
+        }
+        self.newline();
+    }
+
+    fn close(&mut self, empty: bool, bracket: char) {
+        self.depth -= 1;
+        if !empty {
+            self.newline();
+        }
+        self.out.push(bracket);
+    }
+
+    fn newline(&mut self) {
+        if self.indent > 0 {
+            self.out.push('\n');
+            self.out.push_str(&" ".repeat(self.indent * self.depth));
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        self.out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                c if (c as u32) < 0x20 => self.out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+fn error(message: impl Into<String>) -> StringifyError {
+    StringifyError {
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(text: &str) -> String {
+        stringify(&parse(text).unwrap(), 0).unwrap()
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(parse("null"), Ok(Value::Nil));
+        assert_eq!(parse(" true "), Ok(Value::Boolean(true)));
+        assert_eq!(parse("-1.5e2"), Ok(Value::Number(-150.0)));
+        assert_eq!(
+            parse(r#""a\"b\\c\né😀""#),
+            Ok(Value::String("a\"b\\c\né😀".to_string()))
+        );
+        assert_eq!(
+            roundtrip(r#"{"b": [1, 2, {}], "a": null, "c": []}"#),
+            r#"{"a":null,"b":[1,2,{}],"c":[]}"#
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_positions() {
+        let error = |text| parse(text).unwrap_err();
+        assert_eq!(
+            error("[1,\n  2,,]"),
+            ParseError {
+                message: "expected a value, found ','".to_string(),
+                line: 2,
+                column: 5
+            }
+        );
+        assert_eq!(error("{\"é\": tru}").column, 7);
+        assert_eq!(error("\"abc").message, "unterminated string");
+        assert_eq!(error("01").message, "unexpected text after the value");
+        assert_eq!(error("1.").message, "expected a digit, found the end of the text");
+        assert_eq!(error("{1: 2}").message, "expected a string, found '1'");
+        assert_eq!(error(&"[".repeat(1000)).message, "too deeply nested");
+    }
+
+    #[test]
+    fn stringify_with_indent() {
+        let value = parse(r#"{"a": [1, "x"], "b": {}}"#).unwrap();
+        assert_eq!(
+            stringify(&value, 2).unwrap(),
+            "{\n  \"a\": [\n    1,\n    \"x\"\n  ],\n  \"b\": {}\n}"
+        );
+    }
+
+    #[test]
+    fn stringify_errors() {
+        assert_eq!(
+            stringify(&Value::Number(f64::NAN), 0),
+            Err(error("cannot convert nan to JSON"))
+        );
+        let list = Value::list(vec![]);
+        if let Value::List(values) = &list {
+            values.borrow_mut().push(list.clone());
+        }
+        assert_eq!(
+            stringify(&list, 0),
+            Err(error("cannot convert a value that contains itself to JSON"))
+        );
+        // Break the cycle, so the list is freed.
+        if let Value::List(values) = &list {
+            values.borrow_mut().clear();
+        }
+    }
+}
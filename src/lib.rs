@@ -0,0 +1,53 @@
+//! A tree-walking interpreter for Lox, from
+//! ["Crafting Interpreters"](http://craftinginterpreters.com).
+//!
+//! ```
+//! use rlox::{Interpreter, Value};
+//!
+//! let mut interpreter = Interpreter::new();
+//! interpreter.set_global("radius", 2.0);
+//! interpreter
+//!     .run_source("fun area(r) { return 3 * r * r; }")
+//!     .unwrap();
+//! assert_eq!(interpreter.eval_expr("area(radius)"), Ok(Value::Number(12.0)));
+//! assert_eq!(
+//!     interpreter.call_function("area", vec![Value::Number(1.0)]),
+//!     Ok(Value::Number(3.0))
+//! );
+//! ```
+
+#[macro_use]
+extern crate lalrpop_util;
+
+// So that the derive macros, which refer to `::rlox`, work inside this crate.
+extern crate self as rlox;
+
+lalrpop_mod!(#[allow(clippy::all)] pub lox);
+
+pub mod ast;
+pub mod bindings;
+pub mod capabilities;
+pub mod convert;
+pub mod environment;
+pub mod error;
+pub mod format;
+pub mod gc;
+pub mod interpreter;
+pub mod json;
+pub mod module;
+pub mod runtime;
+pub mod source_map;
+pub mod value;
+
+mod test;
+
+pub use capabilities::{Capabilities, Capability};
+pub use environment::Environment;
+pub use error::{Error, NativeError, RuntimeError};
+pub use gc::{GcStats, HeapStats};
+pub use interpreter::Interpreter;
+pub use runtime::{Clock, Input, InterruptHandle, Limits, Output};
+pub use source_map::SourceMap;
+pub use value::{Arity, Value};
+
+pub use rlox_derive::{FromValue, IntoValue};
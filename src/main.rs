@@ -1,40 +1,183 @@
-#[macro_use]
-extern crate lalrpop_util;
-lalrpop_mod!(pub lox);
+mod repl;
 
-mod ast;
-mod bindings;
-mod environment;
-mod error;
-mod interpreter;
-mod value;
-
-mod test;
-
-use environment::Environment;
-use interpreter::interpret_source;
-use value::Value;
+use rlox::error;
+use rlox::{Capabilities, Capability, Clock, Error, GcStats, HeapStats, Interpreter, Limits};
 
 use clap::Parser;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
 
 #[derive(Parser)]
 struct Opts {
-    #[clap(value_parser)]
-    input: String,
+    /// The script to run ('-' reads it from stdin); if omitted, starts an
+    /// interactive session.
+    #[clap(value_parser, conflicts_with = "eval")]
+    input: Option<String>,
+
+    /// Run the given source code instead of a script.
+    #[clap(short, long, value_parser)]
+    eval: Option<String>,
 
     #[clap(long, action)]
     simple_errors: bool,
+
+    /// Make the script behave the same every time it's run: `clock()` starts
+    /// at zero and goes up by one each time it's called, and `random()` is
+    /// always seeded the same way.
+    #[clap(long, action)]
+    deterministic: bool,
+
+    /// Collect garbage at every opportunity; slow, but good for finding bugs
+    /// in the collector.
+    #[clap(long, action)]
+    gc_stress: bool,
+
+    /// When the script finishes, print what the garbage collector did.
+    #[clap(long, action)]
+    gc_stats: bool,
+
+    /// When the script finishes, print how much memory it used, and on what.
+    #[clap(long, action)]
+    mem_report: bool,
+
+    /// Only allow the script these capabilities: a comma-separated list of
+    /// pure, time, fs-read, fs-write, env, process and stdin. Defaults to all
+    /// of them.
+    #[clap(long, value_parser, value_delimiter = ',')]
+    capabilities: Option<Vec<Capability>>,
+
+    /// Stop after evaluating this many expressions.
+    #[clap(long, value_parser)]
+    fuel: Option<u64>,
+
+    /// Limit how deeply functions may call each other.
+    #[clap(long, value_parser)]
+    max_call_depth: Option<usize>,
+
+    /// Limit (roughly) how many bytes variables may take up.
+    #[clap(long, value_parser)]
+    max_memory: Option<usize>,
+
+    /// Stop after running for this many milliseconds.
+    #[clap(long, value_parser)]
+    timeout: Option<u64>,
+
+    /// Arguments passed to the script, available from `args()`.
+    #[clap(last = true, value_parser)]
+    args: Vec<String>,
 }
 
+// Lox calls recurse on the Rust stack, so give scripts plenty of it, and let
+// them use most of it before reporting a stack overflow.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+const SCRIPT_STACK_SIZE: usize = STACK_SIZE / 4 * 3;
+
 fn main() {
     let opts = Opts::parse();
+    let interpreter = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(opts))
+        .expect("spawn interpreter thread");
+    match interpreter.join() {
+        Ok(code) => std::process::exit(code),
+        // A bug in rlox rather than in the script, so don't pass it off as a
+        // runtime error; the message has already been printed.
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+fn run(opts: Opts) -> i32 {
+    // Imports in a script are relative to it; elsewhere, to the current
+    // directory.
+    let is_file = opts.eval.is_none() && opts.input.as_deref() != Some("-");
+    let (path, source) = match (opts.eval, opts.input) {
+        (Some(source), _) => ("<eval>".to_string(), Ok(source)),
+        (None, Some(input)) if input == "-" => ("<stdin>".to_string(), read_stdin()),
+        (None, Some(input)) => {
+            let source = std::fs::read_to_string(&input);
+            (input, source)
+        }
+        (None, None) => return repl::run(opts.simple_errors).expect("repl"),
+    };
 
-    let source = std::fs::read_to_string(&opts.input).expect("read file");
+    let source = match source {
+        Ok(source) => source,
+        Err(e) => {
+            error::report_io_error(&path, opts.simple_errors, &e);
+            return error::EXIT_IO_ERROR;
+        }
+    };
 
-    let mut environment = Environment::new();
-    bindings::register_globals(&mut environment);
-    match interpret_source(&source, &mut environment) {
-        Ok(_) => {}
-        Err(e) => error::report_error(&opts.input, &source, opts.simple_errors, e),
+    let capabilities = match opts.capabilities {
+        Some(capabilities) => capabilities.into_iter().collect(),
+        None => Capabilities::all(),
+    };
+    let mut interpreter = Interpreter::with_capabilities(&opts.args, &capabilities);
+    interpreter.set_limits(Limits {
+        fuel: opts.fuel,
+        max_call_depth: opts.max_call_depth,
+        max_memory: opts.max_memory,
+        timeout: opts.timeout.map(Duration::from_millis),
+        stack_size: Some(SCRIPT_STACK_SIZE),
+    });
+    interpreter.set_gc_stress(opts.gc_stress);
+    if opts.deterministic {
+        interpreter.set_clock(Clock::stepping(0.0, 1.0));
+        interpreter.seed_random(0);
+    }
+    let result = if is_file {
+        interpreter.run_script(Path::new(&path), &source)
+    } else {
+        interpreter.run_named(&path, &source)
+    };
+    let code = match result {
+        Ok(_) => 0,
+        Err(Error::Exit(code)) => code,
+        Err(e) => {
+            let code = e.exit_code();
+            error::report_error(&interpreter.sources(), opts.simple_errors, e);
+            code
+        }
     };
+
+    if opts.gc_stats {
+        print_gc_stats(&interpreter.gc_stats());
+    }
+    if opts.mem_report {
+        print_mem_report(&interpreter.heap_stats());
+    }
+    code
+}
+
+// The reports go to stderr, so that they don't get mixed up with the script's
+// output.
+fn print_gc_stats(stats: &GcStats) {
+    eprintln!(
+        "gc: {} collections, freeing {} environments and {} lists and maps",
+        stats.collections, stats.scopes_freed, stats.values_freed
+    );
+}
+
+fn print_mem_report(stats: &HeapStats) {
+    eprintln!("{:<14} {:>10} {:>10}", "", "live", "peak");
+    eprintln!("{:<14} {:>10} {:>10}", "bytes", stats.memory.live, stats.memory.peak);
+    eprintln!("{:<14} {:>10} {:>10}", "environments", stats.scopes.live, stats.scopes.peak);
+    eprintln!(
+        "{:<14} {:>10} {:>10}",
+        "string bytes", stats.string_bytes.live, stats.string_bytes.peak
+    );
+    for (type_name, count) in &stats.values {
+        eprintln!("{:<14} {:>10} {:>10}", type_name.to_string() + "s", count.live, count.peak);
+    }
+    eprintln!(
+        "{} environments allocated, {} collections",
+        stats.scopes_allocated, stats.gc.collections
+    );
+}
+
+fn read_stdin() -> std::io::Result<String> {
+    let mut source = String::new();
+    std::io::stdin().read_to_string(&mut source)?;
+    Ok(source)
 }
@@ -0,0 +1,167 @@
+//! `import "path.lox" as name;` runs another file, once, in an environment of
+//! its own, and defines `name` as a module through which the importer can get
+//! at what the file defined, as `name.thing`. Paths are relative to the file
+//! doing the importing.
+
+use crate::ast::Location;
+use crate::environment::Environment;
+use crate::error::{describe_parse_error, Error, RuntimeError};
+use crate::interpreter::{interpret_statements, parse_program};
+use crate::value::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The name under which the runtime records whether `import` is disabled
+/// (see `Runtime::disabled_capability`). It's a keyword, so it can't clash
+/// with a native's name.
+pub(crate) const IMPORT: &str = "import";
+
+/// The modules known to one interpreter.
+#[derive(Default)]
+pub struct Modules {
+    /// The modules that have been run, by canonical path.
+    loaded: HashMap<PathBuf, Value>,
+    /// The files being run, each importing the next, so that cycles can be
+    /// found.
+    loading: Vec<Loading>,
+}
+
+struct Loading {
+    canonical: PathBuf,
+    /// The name of the file, as it appears in diagnostics.
+    name: String,
+    /// The `import` that's running it; `None` for the file that started it
+    /// all.
+    imported_at: Option<Location>,
+}
+
+impl Modules {
+    /// Forgets the modules that have been run, so that they can be freed.
+    /// The runtime holds them, and they hold the runtime, so this has to be
+    /// done by hand.
+    pub fn unload(&mut self) -> HashMap<PathBuf, Value> {
+        std::mem::take(&mut self.loaded)
+    }
+
+    /// If `canonical` is already being run, the files in the cycle and the
+    /// imports that led from each to the next.
+    fn cycle(&self, canonical: &Path, name: &str) -> Option<(Vec<String>, Vec<Location>)> {
+        let start = self.loading.iter().position(|l| l.canonical == canonical)?;
+        let chain = &self.loading[start..];
+        let mut cycle: Vec<String> = chain.iter().map(|l| l.name.clone()).collect();
+        cycle.push(name.to_string());
+        let imports = chain[1..].iter().filter_map(|l| l.imported_at).collect();
+        Some((cycle, imports))
+    }
+}
+
+/// Runs `import "path" as name;`, which appears at `location`.
+pub(crate) fn import<'s>(
+    path: &str,
+    name: &str,
+    location: Location,
+    environment: &mut Environment,
+) -> Result<(), Error<'s>> {
+    let runtime = environment.runtime().clone();
+    if let Some(capability) = runtime.disabled_capability(IMPORT) {
+        return Err(Error::Runtime(RuntimeError::CapabilityDisabled {
+            name: IMPORT.to_string(),
+            capability,
+            location,
+        }));
+    }
+    let outermost = runtime.modules().borrow().loading.is_empty();
+    if outermost {
+        // The importing file can be part of a cycle too.
+        let sources = runtime.sources().borrow();
+        let canonical = sources.path(location.file).and_then(|p| p.canonicalize().ok());
+        if let (Some(canonical), Some(name)) = (canonical, sources.name(location.file)) {
+            runtime.modules().borrow_mut().loading.push(Loading {
+                canonical,
+                name: name.to_string(),
+                imported_at: None,
+            });
+        }
+    }
+    let result = import_file(path, name, location, environment);
+    if outermost {
+        runtime.modules().borrow_mut().loading.clear();
+    }
+    result
+}
+
+fn import_file<'s>(
+    path: &str,
+    name: &str,
+    location: Location,
+    environment: &mut Environment,
+) -> Result<(), Error<'s>> {
+    let runtime = environment.runtime().clone();
+    let resolved = {
+        let sources = runtime.sources().borrow();
+        let base = sources.path(location.file).and_then(Path::parent);
+        base.map_or_else(|| PathBuf::from(path), |base| base.join(path))
+    };
+    let display = resolved.display().to_string();
+    let io_error = |e: std::io::Error| {
+        Error::Runtime(RuntimeError::Io {
+            message: format!("cannot import '{}': {}", display, e),
+            location,
+        })
+    };
+    let canonical = resolved.canonicalize().map_err(io_error)?;
+
+    let loaded = runtime.modules().borrow().loaded.get(&canonical).cloned();
+    if let Some(module) = loaded {
+        environment.define(name, module);
+        return Ok(());
+    }
+    if let Some((cycle, imports)) = runtime.modules().borrow().cycle(&canonical, &display) {
+        return Err(Error::Runtime(RuntimeError::ImportCycle {
+            cycle,
+            imports,
+            location,
+        }));
+    }
+
+    let source = std::fs::read_to_string(&resolved).map_err(io_error)?;
+    let file = runtime
+        .sources()
+        .borrow_mut()
+        .add(display.clone(), &source, Some(&resolved));
+    // The errors refer to `source`, which is about to go, so describe them
+    // now.
+    let program = match parse_program(file, &source) {
+        Ok(program) => program,
+        Err(Error::Parse { file, errors }) => {
+            let errors = errors.iter().map(|e| describe_parse_error(file, e)).collect();
+            return Err(Error::Runtime(RuntimeError::Syntax { errors }));
+        }
+        Err(Error::Runtime(e)) => return Err(Error::Runtime(e)),
+        Err(_) => unreachable!("parsing doesn't run anything"),
+    };
+
+    // Modules can see the natives, but not the globals of whoever imported
+    // them.
+    let mut globals = Environment::with_enclosing(&environment.root());
+    runtime.modules().borrow_mut().loading.push(Loading {
+        canonical: canonical.clone(),
+        name: display.clone(),
+        imported_at: Some(location),
+    });
+    let result = interpret_statements(&program, &mut globals);
+    runtime.modules().borrow_mut().loading.pop();
+    result?;
+
+    let module = Value::Module {
+        path: display,
+        globals,
+    };
+    runtime
+        .modules()
+        .borrow_mut()
+        .loaded
+        .insert(canonical, module.clone());
+    environment.define(name, module);
+    Ok(())
+}
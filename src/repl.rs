@@ -0,0 +1,286 @@
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use std::path::{Path, PathBuf};
+
+use rlox::error;
+use rlox::interpreter;
+use rlox::{Environment, Interpreter, SourceMap};
+
+const KEYWORDS: &[&str] = &[
+    "as", "assert", "else", "false", "for", "fun", "if", "import", "nil", "print", "return",
+    "true", "var", "while",
+];
+
+const COMMANDS: &[&str] = &[":ast", ":env", ":help", ":load", ":quit", ":reset"];
+
+const HISTORY_FILE: &str = ".rlox_history";
+
+struct LoxHelper {
+    // Environment clones share the same scope, so this sees every definition
+    // made at the prompt.
+    environment: Environment,
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let prefix = &line[start..pos];
+        if prefix.is_empty() {
+            return Ok((pos, Vec::new()));
+        }
+
+        let candidates = if prefix.starts_with(':') {
+            COMMANDS
+                .iter()
+                .filter(|c| c.starts_with(prefix))
+                .map(|c| c.to_string())
+                .collect()
+        } else {
+            let mut candidates: Vec<String> = KEYWORDS
+                .iter()
+                .map(|k| k.to_string())
+                .chain(self.environment.names())
+                .filter(|c| c.starts_with(prefix))
+                .collect();
+            candidates.sort();
+            candidates.dedup();
+            candidates
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {}
+
+impl Validator for LoxHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        // Keep reading lines while there are unclosed brackets, so that
+        // functions and blocks can be entered over several lines.
+        if is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for LoxHelper {}
+
+fn is_incomplete(input: &str) -> bool {
+    if input.starts_with(':') {
+        return false;
+    }
+
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => in_comment = false,
+            _ if in_comment => {}
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '/' if chars.peek() == Some(&'/') => in_comment = true,
+            '{' | '(' => depth += 1,
+            '}' | ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    in_string || depth > 0
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+/// Runs the REPL until end of input, `:quit` or a call to `exit`. Returns the
+/// process exit code.
+pub fn run(simple_errors: bool) -> rustyline::Result<i32> {
+    let mut interpreter = Interpreter::new();
+    let mut editor: Editor<LoxHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(LoxHelper {
+        environment: interpreter.globals(),
+    }));
+
+    let history = history_path();
+    if let Some(path) = &history {
+        // It's fine if there's no history yet.
+        let _ = editor.load_history(path);
+    }
+
+    let mut code = 0;
+    // Each line is a source of its own, so that errors can point at earlier
+    // ones.
+    let mut line_number = 0;
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        if let Some(command) = line.strip_prefix(':') {
+            let (command, arg) = match command.split_once(char::is_whitespace) {
+                Some((command, arg)) => (command, arg.trim()),
+                None => (command, ""),
+            };
+            match command {
+                "quit" | "q" => break,
+                "help" => print_help(),
+                "env" => print_environment(&interpreter.globals()),
+                "ast" => print_ast(arg, simple_errors),
+                "load" => {
+                    if let Some(c) = load_file(arg, &mut interpreter, simple_errors) {
+                        code = c;
+                        break;
+                    }
+                }
+                "reset" => {
+                    interpreter = Interpreter::new();
+                    if let Some(helper) = editor.helper_mut() {
+                        helper.environment = interpreter.globals();
+                    }
+                }
+                _ => eprintln!("unknown command ':{}'; try ':help'", command),
+            }
+            continue;
+        }
+
+        line_number += 1;
+        let name = format!("<repl {}>", line_number);
+        if let Some(c) = evaluate_line(&name, line, &mut interpreter, simple_errors) {
+            code = c;
+            break;
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(code)
+}
+
+/// Evaluates a line of input, returning the exit code if it called `exit`.
+fn evaluate_line(
+    name: &str,
+    line: &str,
+    interpreter: &mut Interpreter,
+    simple_errors: bool,
+) -> Option<i32> {
+    // A bare expression (without the trailing semicolon) is evaluated and its
+    // value printed; anything else is treated as a sequence of declarations.
+    match interpreter.eval_or_run(name, line) {
+        Ok(Some(value)) => {
+            println!("{}", value);
+            None
+        }
+        Ok(None) => None,
+        Err(error::Error::Exit(code)) => Some(code),
+        Err(e) => {
+            error::report_error(&interpreter.sources(), simple_errors, e);
+            None
+        }
+    }
+}
+
+fn print_help() {
+    println!(":ast <expr>   show the syntax tree for an expression or statement");
+    println!(":env          list the names defined in the environment");
+    println!(":help         show this message");
+    println!(":load <file>  run a file in the current environment");
+    println!(":quit         exit the REPL");
+    println!(":reset        discard everything defined so far");
+}
+
+fn print_environment(environment: &Environment) {
+    for name in environment.names() {
+        if let Some(value) = environment.get(&name) {
+            println!("{} = {}", name, value);
+        }
+    }
+}
+
+fn print_ast(source: &str, simple_errors: bool) {
+    let mut sources = SourceMap::new();
+    let file = sources.add("<ast>", source, None);
+    match interpreter::parse_expr(file, source) {
+        Ok(expr) => println!("{:#?}", expr),
+        Err(_) => match interpreter::parse_program(file, source) {
+            Ok(program) => println!("{:#?}", program),
+            Err(e) => error::report_error(&sources, simple_errors, e),
+        },
+    }
+}
+
+/// Runs a file, returning the exit code if it called `exit`.
+fn load_file(path: &str, interpreter: &mut Interpreter, simple_errors: bool) -> Option<i32> {
+    if path.is_empty() {
+        eprintln!("usage: :load <file>");
+        return None;
+    }
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            error::report_io_error(path, simple_errors, &e);
+            return None;
+        }
+    };
+    match interpreter.run_script(Path::new(path), &source) {
+        Ok(()) => None,
+        Err(error::Error::Exit(code)) => Some(code),
+        Err(e) => {
+            error::report_error(&interpreter.sources(), simple_errors, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_incomplete;
+
+    #[test]
+    fn balanced_is_complete() {
+        assert!(!is_incomplete("print 1;"));
+        assert!(!is_incomplete("fun f() { return 1; }"));
+    }
+
+    #[test]
+    fn open_brace_is_incomplete() {
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("if (true) {\n  print 1;"));
+    }
+
+    #[test]
+    fn brackets_in_strings_and_comments_are_ignored() {
+        assert!(!is_incomplete(r#"print "{";"#));
+        assert!(!is_incomplete("print 1; // {"));
+        assert!(is_incomplete("{ // }"));
+    }
+}
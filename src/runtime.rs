@@ -0,0 +1,519 @@
+//! State shared by everything running in one interpreter, such as where
+//! `print` writes to and how much longer a script may run for. Every
+//! environment holds a reference to it, so it's available wherever an
+//! environment is.
+
+use crate::ast::Location;
+use crate::capabilities::Capability;
+use crate::error::RuntimeError;
+use crate::gc::{GcStats, Heap, HeapStats};
+use crate::module::Modules;
+use crate::source_map::SourceMap;
+use crate::value::MAX_NESTING;
+use crate::Value;
+use regex::Regex;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// By default, the interpreter may use this much of the Rust stack. It
+/// recurses on the Rust stack, and a deeply-nested Lox call can take tens of
+/// kilobytes in a debug build, so this is what actually prevents overflow.
+/// It's half the size of the stack Rust gives to new threads.
+pub const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
+
+/// How many compiled regular expressions to keep. When the cache is full, it's
+/// emptied; scripts rarely use more than a handful of patterns, unless
+/// they're building them on the fly.
+const REGEX_CACHE_SIZE: usize = 256;
+
+/// How many steps to take between looking at the clock.
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+/// Limits on how much work a script may do. Each applies separately to each
+/// call to `run_source`, `eval_expr` or `call_function`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// How many expressions may be evaluated.
+    pub fuel: Option<u64>,
+    /// How deeply Lox functions may call each other.
+    pub max_call_depth: Option<usize>,
+    /// How many bytes of the Rust stack may be used.
+    pub stack_size: Option<usize>,
+    /// Roughly how many bytes variables, and the values they hold, may take
+    /// up. See `Interpreter::memory_usage`.
+    pub max_memory: Option<usize>,
+    /// How long a script may run for.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            max_call_depth: None,
+            stack_size: Some(DEFAULT_STACK_SIZE),
+            max_memory: None,
+            timeout: None,
+        }
+    }
+}
+
+/// Stops a running script from another thread. The script stops, with
+/// `RuntimeError::Interrupted`, the next time it goes round a loop or calls a
+/// function. If nothing is running, it does nothing: every script starts out
+/// uninterrupted, so a handle can be shared with a REPL or a server.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where `clock()` gets the time from, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    /// The system clock. This is the default.
+    Real,
+    /// Always the same time.
+    Fixed(f64),
+    /// Starts at `next`, and moves on by `step` each time it's read.
+    Stepping { next: f64, step: f64 },
+}
+
+impl Clock {
+    pub fn stepping(start: f64, step: f64) -> Self {
+        Clock::Stepping { next: start, step }
+    }
+
+    fn now(&mut self) -> f64 {
+        match self {
+            Clock::Real => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as f64,
+            Clock::Fixed(t) => *t,
+            Clock::Stepping { next, step } => {
+                let now = *next;
+                *next += *step;
+                now
+            }
+        }
+    }
+}
+
+/// Where the output of `print` goes.
+pub enum Output {
+    /// The process's standard output. This is the default.
+    Stdout,
+    /// Collected in memory; see `Interpreter::take_output`.
+    Buffer(Vec<u8>),
+    /// Any other writer, such as a file or a socket.
+    Writer(Box<dyn Write>),
+    /// Called with each printed line, without the trailing newline, and with
+    /// each prompt written by `input`.
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+impl Output {
+    fn print(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout => writeln!(io::stdout().lock(), "{}", line),
+            Output::Buffer(buffer) => writeln!(buffer, "{}", line),
+            Output::Writer(writer) => writeln!(writer, "{}", line),
+            Output::Callback(callback) => {
+                callback(line);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `text` without a newline, flushing it so that it's seen.
+    fn prompt(&mut self, text: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout => {
+                let mut stdout = io::stdout().lock();
+                write!(stdout, "{}", text)?;
+                stdout.flush()
+            }
+            Output::Buffer(buffer) => write!(buffer, "{}", text),
+            Output::Writer(writer) => {
+                write!(writer, "{}", text)?;
+                writer.flush()
+            }
+            Output::Callback(callback) => {
+                callback(text);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Where `read_line`, `read_all` and `input` read from.
+pub enum Input {
+    /// The process's standard input. This is the default.
+    Stdin,
+    /// Any other reader, such as a file or, in tests, a `Cursor`.
+    Reader(Box<dyn BufRead>),
+}
+
+impl Input {
+    /// Reads from `text`.
+    pub fn text(text: impl Into<String>) -> Self {
+        Input::Reader(Box::new(io::Cursor::new(text.into().into_bytes())))
+    }
+
+    /// The next line, without its line ending, or `None` at the end of the
+    /// input.
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let n = match self {
+            Input::Stdin => io::stdin().lock().read_line(&mut line)?,
+            Input::Reader(reader) => reader.read_line(&mut line)?,
+        };
+        if n == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    fn read_all(&mut self) -> io::Result<String> {
+        let mut text = String::new();
+        match self {
+            Input::Stdin => io::stdin().lock().read_to_string(&mut text)?,
+            Input::Reader(reader) => reader.read_to_string(&mut text)?,
+        };
+        Ok(text)
+    }
+}
+
+/// The longest string that natives will build, whatever the memory limit,
+/// so that a mistake in a script can't exhaust the machine's memory.
+pub const MAX_STRING_LENGTH: usize = 1 << 30;
+
+pub struct Runtime {
+    output: RefCell<Output>,
+    input: RefCell<Input>,
+    limits: Cell<Limits>,
+    steps: Cell<u64>,
+    depth: Cell<usize>,
+    stack_base: Cell<usize>,
+    deadline: Cell<Option<Instant>>,
+    interrupt: InterruptHandle,
+    disabled: RefCell<HashMap<String, Capability>>,
+    clock: Cell<Clock>,
+    random_state: Cell<u64>,
+    regexes: RefCell<HashMap<String, Regex>>,
+    modules: RefCell<Modules>,
+    sources: RefCell<SourceMap>,
+    heap: Heap,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self {
+            output: RefCell::new(Output::Stdout),
+            input: RefCell::new(Input::Stdin),
+            limits: Cell::new(Limits::default()),
+            steps: Cell::new(0),
+            depth: Cell::new(0),
+            stack_base: Cell::new(stack_position()),
+            deadline: Cell::new(None),
+            interrupt: InterruptHandle::default(),
+            disabled: RefCell::new(HashMap::new()),
+            clock: Cell::new(Clock::Real),
+            random_state: Cell::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64,
+            ),
+            regexes: RefCell::new(HashMap::new()),
+            modules: RefCell::new(Modules::default()),
+            sources: RefCell::new(SourceMap::new()),
+            heap: Heap::default(),
+        }
+    }
+}
+
+impl Runtime {
+    pub fn set_limits(&self, limits: Limits) {
+        self.limits.set(limits);
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits.get()
+    }
+
+    /// Resets the fuel, the timeout and any interruption, ready to run
+    /// another script.
+    pub fn start(&self) {
+        self.interrupt.interrupted.store(false, Ordering::Relaxed);
+        self.steps.set(0);
+        self.depth.set(0);
+        self.stack_base.set(stack_position());
+        self.deadline
+            .set(self.limits.get().timeout.map(|t| Instant::now() + t));
+    }
+
+    /// Accounts for one evaluation step, at `location`.
+    // `is_multiple_of` needs Rust 1.87.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn step(&self, location: Location) -> Result<(), RuntimeError> {
+        self.check_memory(0, location)?;
+        self.check_stack(location)?;
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if let Some(fuel) = self.limits.get().fuel {
+            if steps > fuel {
+                return Err(RuntimeError::FuelExhausted { fuel, location });
+            }
+        }
+        if steps % STEPS_PER_CLOCK_CHECK == 0 {
+            if let Some(deadline) = self.deadline.get() {
+                if Instant::now() >= deadline {
+                    return Err(RuntimeError::Timeout { location });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn allocate(&self, bytes: usize) {
+        self.heap.allocated(bytes);
+    }
+
+    pub fn free(&self, bytes: usize) {
+        self.heap.freed(bytes);
+    }
+
+    /// Roughly how many bytes are in use by environments and the values in
+    /// them.
+    pub fn memory_usage(&self) -> usize {
+        self.heap.memory_usage()
+    }
+
+    /// Fails if allocating another `bytes` would go over the memory limit,
+    /// even after collecting garbage.
+    pub fn check_memory(&self, bytes: usize, location: Location) -> Result<(), RuntimeError> {
+        self.fits_in_memory(bytes)
+            .map_err(|limit| RuntimeError::OutOfMemory { limit, location })
+    }
+
+    /// Fails, saying why, if a native shouldn't build a string of `bytes`
+    /// bytes: it's longer than `MAX_STRING_LENGTH`, or would go over the
+    /// memory limit.
+    pub fn check_string_length(&self, bytes: usize) -> Result<(), String> {
+        if bytes > MAX_STRING_LENGTH {
+            return Err(format!("the result would be longer than {} bytes", MAX_STRING_LENGTH));
+        }
+        self.fits_in_memory(bytes).map_err(|limit| {
+            format!("the result would go over the memory limit of {} bytes", limit)
+        })
+    }
+
+    /// Fails with the limit if another `bytes` won't fit under it.
+    fn fits_in_memory(&self, bytes: usize) -> Result<(), usize> {
+        let limit = match self.limits.get().max_memory {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.memory_usage().saturating_add(bytes) > limit {
+            crate::gc::collect(self);
+        }
+        if self.memory_usage().saturating_add(bytes) > limit {
+            return Err(limit);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.heap_stats()
+    }
+
+    pub fn set_clock(&self, clock: Clock) {
+        self.clock.set(clock);
+    }
+
+    /// The time, in milliseconds, according to the clock.
+    pub fn now(&self) -> f64 {
+        let mut clock = self.clock.get();
+        let now = clock.now();
+        self.clock.set(clock);
+        now
+    }
+
+    /// Seeds the random number generator, so that `random()` returns the
+    /// same sequence every time. By default, it's seeded from the time.
+    pub fn seed_random(&self, seed: u64) {
+        self.random_state.set(seed);
+    }
+
+    /// A random number in [0, 1), using SplitMix64; it doesn't need to be
+    /// good, just reproducible.
+    pub fn random(&self) -> f64 {
+        let state = self.random_state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.random_state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // The top 53 bits, as a fraction.
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Compiles `pattern`, or returns it from the cache if it's been compiled
+    /// before.
+    pub fn regex(&self, pattern: &str) -> Result<Regex, regex::Error> {
+        let mut regexes = self.regexes.borrow_mut();
+        if let Some(regex) = regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Regex::new(pattern)?;
+        if regexes.len() >= REGEX_CACHE_SIZE {
+            regexes.clear();
+        }
+        regexes.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+
+    pub(crate) fn modules(&self) -> &RefCell<Modules> {
+        &self.modules
+    }
+
+    pub(crate) fn sources(&self) -> &RefCell<SourceMap> {
+        &self.sources
+    }
+
+    /// Records that the native `name` wasn't defined because `capability`
+    /// is disabled.
+    pub fn disable(&self, name: &str, capability: Capability) {
+        self.disabled.borrow_mut().insert(name.to_string(), capability);
+    }
+
+    /// Records that the native `name` has been defined after all.
+    pub fn enable(&self, name: &str) {
+        self.disabled.borrow_mut().remove(name);
+    }
+
+    /// If `name` is a native that wasn't defined, the capability it needs.
+    pub fn disabled_capability(&self, name: &str) -> Option<Capability> {
+        self.disabled.borrow().get(name).copied()
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Fails if the script has been interrupted, clearing the interruption.
+    pub fn check_interrupt(&self, location: Location) -> Result<(), RuntimeError> {
+        if self.interrupt.interrupted.swap(false, Ordering::Relaxed) {
+            return Err(RuntimeError::Interrupted { location });
+        }
+        Ok(())
+    }
+
+    /// Called on entry to a Lox function; fails if there are already too many
+    /// nested calls, or they're using too much stack. Every successful call
+    /// must be matched by `leave_call`.
+    pub fn enter_call(&self, location: Location) -> Result<(), RuntimeError> {
+        self.check_interrupt(location)?;
+        let limits = self.limits.get();
+        let depth = self.depth.get();
+        if limits.max_call_depth.is_some_and(|max| depth >= max) {
+            return Err(RuntimeError::StackOverflow { depth, location });
+        }
+        self.check_stack(location)?;
+        self.depth.set(depth + 1);
+        Ok(())
+    }
+
+    /// Fails if the interpreter is using too much of the Rust stack. Deeply
+    /// nested expressions and blocks use it up without calling anything, so
+    /// every step checks this, as well as every call.
+    pub fn check_stack(&self, location: Location) -> Result<(), RuntimeError> {
+        let used = self.stack_base.get().abs_diff(stack_position());
+        if self.limits.get().stack_size.is_some_and(|max| used > max) {
+            return Err(RuntimeError::StackOverflow { depth: self.depth.get(), location });
+        }
+        Ok(())
+    }
+
+    /// Fails if `value` has lists or maps nested `MAX_NESTING` deep, so that
+    /// a script can't build one too deep to measure, print or drop.
+    pub fn check_nesting(&self, value: &Value, location: Location) -> Result<(), RuntimeError> {
+        if value.nesting(MAX_NESTING) >= MAX_NESTING {
+            return Err(RuntimeError::StackOverflow { depth: self.depth.get(), location });
+        }
+        Ok(())
+    }
+
+    pub fn leave_call(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    pub fn print(&self, line: &str) -> io::Result<()> {
+        self.output.borrow_mut().print(line)
+    }
+
+    pub fn set_output(&self, output: Output) {
+        *self.output.borrow_mut() = output;
+    }
+
+    /// Writes `text` to the output, without starting a new line.
+    pub fn prompt(&self, text: &str) -> io::Result<()> {
+        self.output.borrow_mut().prompt(text)
+    }
+
+    pub fn set_input(&self, input: Input) {
+        *self.input.borrow_mut() = input;
+    }
+
+    /// Reads the next line of input, without its line ending; `None` means
+    /// there's no more.
+    pub fn read_line(&self) -> io::Result<Option<String>> {
+        self.input.borrow_mut().read_line()
+    }
+
+    /// Reads the rest of the input.
+    pub fn read_all(&self) -> io::Result<String> {
+        self.input.borrow_mut().read_all()
+    }
+
+    /// Returns everything printed since the last call, if output is going to
+    /// a buffer; otherwise returns an empty string.
+    pub fn take_output(&self) -> String {
+        match &mut *self.output.borrow_mut() {
+            Output::Buffer(buffer) => String::from_utf8_lossy(&std::mem::take(buffer)).into_owned(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Roughly where the top of the stack is; the stack might grow either way.
+#[inline(never)]
+fn stack_position() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
@@ -0,0 +1,112 @@
+//! The source code an interpreter has run — scripts, the files they import,
+//! REPL lines and `-e` snippets — so that a diagnostic can point at any of
+//! them, even after the code that ran it has returned.
+
+use crate::ast::FileId;
+use codespan_reporting::files::{self, Files, SimpleFile};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+struct SourceFile {
+    file: SimpleFile<String, Rc<str>>,
+    /// Where the file was read from, if it was.
+    path: Option<PathBuf>,
+}
+
+/// The sources known to one interpreter. `FileId`s are indexes into it; once
+/// a source is removed, its id may be given to another.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<Option<SourceFile>>,
+    /// The ids of removed sources, for reuse.
+    free: Vec<FileId>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a source called `name`, which was read from `path` if it's a
+    /// file, and returns its id.
+    pub fn add(&mut self, name: impl Into<String>, source: &str, path: Option<&Path>) -> FileId {
+        let entry = Some(SourceFile {
+            file: SimpleFile::new(name.into(), source.into()),
+            path: path.map(Path::to_path_buf),
+        });
+        match self.free.pop() {
+            Some(file) => {
+                self.files[file] = entry;
+                file
+            }
+            None => {
+                self.files.push(entry);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Forgets the source `file`, once nothing can refer to it any more.
+    pub fn remove(&mut self, file: FileId) {
+        if let Some(entry) = self.files.get_mut(file) {
+            if entry.take().is_some() {
+                self.free.push(file);
+            }
+        }
+    }
+
+    /// How many sources there are, not counting those that were removed.
+    pub fn len(&self) -> usize {
+        self.files.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn name(&self, file: FileId) -> Option<&str> {
+        self.entry(file).map(|f| f.file.name().as_str())
+    }
+
+    pub fn source(&self, file: FileId) -> Option<&str> {
+        self.entry(file).map(|f| f.file.source().as_ref())
+    }
+
+    /// The path that `file` was read from, or `None` if it wasn't a file.
+    pub fn path(&self, file: FileId) -> Option<&Path> {
+        self.entry(file).and_then(|f| f.path.as_deref())
+    }
+
+    fn entry(&self, file: FileId) -> Option<&SourceFile> {
+        self.files.get(file).and_then(Option::as_ref)
+    }
+
+    fn get(&self, file: FileId) -> Result<&SimpleFile<String, Rc<str>>, files::Error> {
+        self.entry(file)
+            .map(|f| &f.file)
+            .ok_or(files::Error::FileMissing)
+    }
+}
+
+impl<'a> Files<'a> for SourceMap {
+    type FileId = FileId;
+    type Name = &'a str;
+    type Source = &'a str;
+
+    fn name(&'a self, file: FileId) -> Result<&'a str, files::Error> {
+        Ok(self.get(file)?.name())
+    }
+
+    fn source(&'a self, file: FileId) -> Result<&'a str, files::Error> {
+        Ok(self.get(file)?.source())
+    }
+
+    fn line_index(&'a self, file: FileId, byte_index: usize) -> Result<usize, files::Error> {
+        self.get(file)?.line_index((), byte_index)
+    }
+
+    fn line_range(&'a self, file: FileId, line_index: usize) -> Result<Range<usize>, files::Error> {
+        self.get(file)?.line_range((), line_index)
+    }
+}
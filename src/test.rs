@@ -4,18 +4,39 @@ use crate::*;
 use crate::error::*;
 
 #[cfg(test)]
-fn parse_string(source: &str) -> Result<ast::Expr, Error> {
-    let parser = lox::ExprParser::new();
-    parser.parse(source).map_err(|e| Error::Parse(e))
+fn parse_string(source: &str) -> Result<ast::Expr, Error<'_>> {
+    interpreter::parse_expr(0, source)
 }
 
 #[cfg(test)]
-fn evaluate_string(source: &str) -> Result<Value, Error> {
+fn parse_program_string(source: &str) -> Result<Vec<ast::Stmt>, Error<'_>> {
+    interpreter::parse_program(0, source)
+}
+
+#[cfg(test)]
+fn evaluate_string(source: &str) -> Result<Value, Error<'_>> {
     let result = parse_string(source);
     let mut environment = Environment::new();
     result.and_then(|expr| interpreter::evaluate(&expr, &mut environment))
 }
 
+/// Runs a program, returning what it printed.
+#[cfg(test)]
+fn run_string(source: &str) -> Result<String, Error<'_>> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    interpreter.run_source(source)?;
+    Ok(interpreter.take_output())
+}
+
+/// Runs a program with an existing interpreter, returning what it printed.
+#[cfg(test)]
+fn run_with(interpreter: &mut Interpreter, source: &str) -> String {
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    interpreter.run_source(source).unwrap();
+    interpreter.take_output()
+}
+
 #[test]
 fn literal_number() {
     assert_eq!(Ok(Value::Number(123.0)), evaluate_string("123"));
@@ -28,14 +49,12 @@ fn paren_number() {
 
 #[test]
 fn paren_imbalanced() {
-    let parser = lox::ExprParser::new();
-    assert!(parser.parse("(123").is_err());
+    assert!(parse_string("(123").is_err());
 }
 
 #[test]
 fn paren_empty() {
-    let parser = lox::ExprParser::new();
-    assert!(parser.parse("()").is_err());
+    assert!(parse_string("()").is_err());
 }
 
 #[test]
@@ -149,12 +168,1203 @@ fn empty_string_literal() {
 fn print_hello() {
     use ast::*;
     let parser = lox::StatementParser::new();
-    let parsed = parser.parse(r#"print "Hello World!";"#);
+    let parsed = parser.parse(0, &mut Vec::new(), r#"print "Hello World!";"#);
     assert_eq!(
         parsed,
         Ok(Stmt::Print(Expr::String {
             value: "Hello World!".to_string(),
-            location: Location { start: 6, end: 20 }
+            location: Location {
+                file: 0,
+                start: 6,
+                end: 20
+            }
+        }))
+    );
+}
+
+#[test]
+fn print_output() {
+    assert_eq!(run_string(r#"print "Hello World!";"#), Ok("Hello World!\n".to_string()));
+    assert_eq!(run_string("print 1 + 2; print nil;"), Ok("3\n<nil>\n".to_string()));
+}
+
+#[test]
+fn print_from_function() {
+    assert_eq!(
+        run_string("fun greet(name) { print \"hi \" + name; } greet(\"bob\"); greet(\"sue\");"),
+        Ok("hi bob\nhi sue\n".to_string())
+    );
+}
+
+#[test]
+fn print_from_closure() {
+    let source = "
+        fun counter() {
+            var n = 0;
+            return fun() { n = n + 1; print n; };
+        }
+        var c = counter();
+        c();
+        c();";
+    assert_eq!(run_string(source), Ok("1\n2\n".to_string()));
+}
+
+#[test]
+fn print_output_is_kept_on_error() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    assert!(interpreter.run_source("print 1; print nope;").is_err());
+    assert_eq!(interpreter.take_output(), "1\n");
+}
+
+#[test]
+fn print_to_callback() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let mut interpreter = Interpreter::new();
+    let sink = lines.clone();
+    interpreter.set_output(Output::Callback(Box::new(move |line| {
+        sink.borrow_mut().push(line.to_string())
+    })));
+    interpreter.run_source("print \"a\"; print [1, 2];").unwrap();
+    assert_eq!(*lines.borrow(), vec!["a", "[1, 2]"]);
+}
+
+#[test]
+fn list_literal() {
+    assert_eq!(
+        evaluate_string("[1, \"two\", nil]"),
+        Ok(Value::list(vec![
+            Value::Number(1.0),
+            Value::String("two".to_string()),
+            Value::Nil
+        ]))
+    );
+    assert_eq!(evaluate_string("[]"), Ok(Value::list(vec![])));
+}
+
+#[test]
+fn list_index() {
+    assert_eq!(evaluate_string("[1, 2, 3][1]"), Ok(Value::Number(2.0)));
+    assert_eq!(evaluate_string("[[1], [2, 3]][1][0]"), Ok(Value::Number(2.0)));
+}
+
+#[test]
+fn lists_and_maps_are_equal_only_to_themselves() {
+    let source = r#"
+        var a = [1];
+        var b = a;
+        var m = json_parse("{}");
+        print a == a; print a == b; print a != a; print a == [1]; print a != [1];
+        print m == m; print m != m; print m == json_parse("{}"); print m != json_parse("{}");
+        print a == m;
+    "#;
+    let expected = "true\ntrue\nfalse\nfalse\ntrue\ntrue\nfalse\nfalse\ntrue\nfalse\n";
+    assert_eq!(run_string(source), Ok(expected.to_string()));
+}
+
+#[test]
+fn list_index_out_of_range() {
+    assert!(matches!(
+        evaluate_string("[1, 2][2]"),
+        Err(Error::Runtime(RuntimeError::IndexOutOfRange { length: 2, .. }))
+    ));
+    assert!(matches!(
+        evaluate_string("[1, 2][0.5]"),
+        Err(Error::Runtime(RuntimeError::IndexOutOfRange { .. }))
+    ));
+}
+
+#[test]
+fn interpreter_keeps_globals() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.run_source("var a = 1;"), Ok(()));
+    assert_eq!(interpreter.run_source("a = a + 1;"), Ok(()));
+    assert_eq!(interpreter.get_global("a"), Some(Value::Number(2.0)));
+    assert_eq!(interpreter.eval_expr("a * 10"), Ok(Value::Number(20.0)));
+}
+
+#[test]
+fn interpreter_set_global() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_global("greeting", "hello");
+    assert_eq!(
+        interpreter.eval_expr(r#"greeting + " world""#),
+        Ok(Value::String("hello world".to_string()))
+    );
+}
+
+#[test]
+fn interpreter_call_function() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .run_source("fun add(a, b) { return a + b; }")
+        .unwrap();
+    assert_eq!(
+        interpreter.call_function("add", vec![Value::Number(1.0), Value::Number(2.0)]),
+        Ok(Value::Number(3.0))
+    );
+    assert!(matches!(
+        interpreter.call_function("add", vec![]),
+        Err(Error::Runtime(RuntimeError::ArityMismatch { .. }))
+    ));
+    assert!(matches!(
+        interpreter.call_function("subtract", vec![]),
+        Err(Error::Runtime(RuntimeError::IdentifierNotFound { .. }))
+    ));
+}
+
+#[test]
+fn call_function_errors_are_located_in_the_call() {
+    let mut interpreter = Interpreter::new();
+    let location = match interpreter.call_function("nonesuch", vec![]) {
+        Err(Error::Runtime(RuntimeError::IdentifierNotFound { location, .. })) => location,
+        other => panic!("unexpected result: {:?}", other),
+    };
+    let sources = interpreter.sources();
+    assert_eq!(sources.name(location.file), Some("<call nonesuch>"));
+    assert_eq!(sources.source(location.file), Some("nonesuch"));
+    assert_eq!((location.start, location.end), (0, 8));
+}
+
+#[test]
+fn unbounded_recursion_is_a_stack_overflow() {
+    let mut interpreter = Interpreter::new();
+    let result = interpreter.run_source("fun f(n) { while (true) { if (true) { { var x = [f(n + 1)]; } } } }\nf(0);");
+    assert!(matches!(
+        result,
+        Err(Error::Runtime(RuntimeError::StackOverflow { .. }))
+    ));
+}
+
+#[test]
+fn deep_nesting_is_a_stack_overflow() {
+    let n = 100_000;
+    let sources = [
+        format!("print {}1;", "-".repeat(n)),
+        format!("print {}1;", "1 + ".repeat(n)),
+        format!("{}{}", "{".repeat(n), "}".repeat(n)),
+        format!("var a = {}1{};", "[".repeat(n), "]".repeat(n)),
+    ];
+    for source in &sources {
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(
+            interpreter.run_source(source),
+            Err(Error::Runtime(RuntimeError::StackOverflow { depth: 0, .. }))
+        ));
+    }
+}
+
+#[test]
+fn lists_can_only_be_nested_so_deeply() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(
+        interpreter.run_source("var n = 0; var a = []; while (true) { a = [a]; n = n + 1; }"),
+        Err(Error::Runtime(RuntimeError::StackOverflow {
+            depth: 0,
+            location: ast::location(43, 44)
+        }))
+    );
+    assert_eq!(interpreter.eval_expr("n"), Ok(Value::Number(511.0)));
+}
+
+#[test]
+fn call_depth_is_configurable() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_limits(Limits {
+        max_call_depth: Some(10),
+        ..Limits::default()
+    });
+    interpreter
+        .run_source("fun depth(n) { if (n == 0) { return 0; } return 1 + depth(n - 1); }")
+        .unwrap();
+    assert_eq!(interpreter.eval_expr("depth(9)"), Ok(Value::Number(9.0)));
+    assert_eq!(
+        interpreter.eval_expr("depth(10)"),
+        Err(Error::Runtime(RuntimeError::StackOverflow {
+            depth: 10,
+            location: ast::location(52, 57)
+        }))
+    );
+    // The depth is reset after an error.
+    assert_eq!(interpreter.eval_expr("depth(9)"), Ok(Value::Number(9.0)));
+}
+
+#[test]
+fn infinite_loop_runs_out_of_fuel() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_limits(Limits {
+        fuel: Some(1000),
+        ..Limits::default()
+    });
+    assert_eq!(
+        interpreter.run_source("while (true) {}"),
+        Err(Error::Runtime(RuntimeError::FuelExhausted {
+            fuel: 1000,
+            location: ast::location(7, 11)
+        }))
+    );
+    // Fuel is per run.
+    assert_eq!(interpreter.eval_expr("1 + 2"), Ok(Value::Number(3.0)));
+}
+
+#[test]
+fn infinite_loop_times_out() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_limits(Limits {
+        timeout: Some(std::time::Duration::from_millis(50)),
+        ..Limits::default()
+    });
+    assert!(matches!(
+        interpreter.run_source("var i = 0; while (true) { i = i + 1; }"),
+        Err(Error::Runtime(RuntimeError::Timeout { .. }))
+    ));
+}
+
+#[test]
+fn interrupt_stops_loop() {
+    let mut interpreter = Interpreter::new();
+    let handle = interpreter.interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        handle.interrupt();
+    });
+    assert_eq!(
+        interpreter.run_source("while (true) {}"),
+        Err(Error::Runtime(RuntimeError::Interrupted {
+            location: ast::location(7, 11)
+        }))
+    );
+    interrupter.join().unwrap();
+    // The interruption only applies once.
+    assert_eq!(interpreter.eval_expr("1 + 2"), Ok(Value::Number(3.0)));
+}
+
+#[test]
+fn interrupt_stops_call() {
+    let mut interpreter = Interpreter::new();
+    let handle = interpreter.interrupt_handle();
+    interpreter.define_native("stop", 0, move |_| {
+        handle.interrupt();
+        Ok(Value::Nil)
+    });
+    interpreter.run_source("fun f() { return 1; }").unwrap();
+    assert_eq!(
+        interpreter.eval_expr("[stop(), f()]"),
+        Err(Error::Runtime(RuntimeError::Interrupted {
+            location: ast::location_in(1, 9, 10)
+        }))
+    );
+    assert_eq!(interpreter.eval_expr("f()"), Ok(Value::Number(1.0)));
+}
+
+#[test]
+fn interrupt_while_idle_is_ignored() {
+    let mut interpreter = Interpreter::new();
+    interpreter.run_source("fun f() { return 1; }").unwrap();
+    interpreter.interrupt_handle().interrupt();
+    assert_eq!(interpreter.eval_expr("f()"), Ok(Value::Number(1.0)));
+    assert_eq!(interpreter.call_function("f", vec![]), Ok(Value::Number(1.0)));
+}
+
+#[test]
+fn memory_usage_grows_and_shrinks() {
+    let mut interpreter = Interpreter::new();
+    let before = interpreter.memory_usage();
+    interpreter.run_source("var s = \"hello\";").unwrap();
+    let after = interpreter.memory_usage();
+    assert!(after > before);
+    interpreter.run_source("s = s + s + s + s;").unwrap();
+    assert_eq!(interpreter.memory_usage(), after + 15);
+    interpreter.run_source("s = nil;").unwrap();
+    assert!(interpreter.memory_usage() < after);
+    // Calls' environments are given back when they return.
+    interpreter
+        .run_source("fun f(n) { var xs = [n, n, n]; return n; } f(1); f(2);")
+        .unwrap();
+    let steady = interpreter.memory_usage();
+    interpreter.run_source("f(3);").unwrap();
+    assert_eq!(interpreter.memory_usage(), steady);
+}
+
+#[test]
+fn growing_string_runs_out_of_memory() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_limits(Limits {
+        max_memory: Some(64 * 1024),
+        ..Limits::default()
+    });
+    let result = interpreter.run_source("var s = \"x\"; while (true) { s = s + s; }");
+    assert!(matches!(
+        result,
+        Err(Error::Runtime(RuntimeError::OutOfMemory { limit: 65536, .. }))
+    ));
+}
+
+#[test]
+fn captured_environments_run_out_of_memory() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_limits(Limits {
+        max_memory: Some(64 * 1024),
+        ..Limits::default()
+    });
+    let source = "
+        var keep = nil;
+        fun wrap(f) { var pad = \"padding\"; return fun() { return f; }; }
+        while (true) { keep = wrap(keep); }";
+    assert!(matches!(
+        interpreter.run_source(source),
+        Err(Error::Runtime(RuntimeError::OutOfMemory { .. }))
+    ));
+}
+
+#[test]
+fn disabled_capability_is_reported() {
+    let mut interpreter = Interpreter::with_capabilities(&[], &Capabilities::pure());
+    assert_eq!(interpreter.eval_expr("len([1, 2])"), Ok(Value::Number(2.0)));
+    assert_eq!(
+        interpreter.eval_expr("getenv(\"HOME\")"),
+        Err(Error::Runtime(RuntimeError::CapabilityDisabled {
+            name: "getenv".to_string(),
+            capability: Capability::Env,
+            location: ast::location(0, 6)
+        }))
+    );
+    assert!(matches!(
+        interpreter.eval_expr("nonesuch()"),
+        Err(Error::Runtime(RuntimeError::IdentifierNotFound { .. }))
+    ));
+}
+
+#[test]
+fn random_needs_time_unless_seeded() {
+    let mut interpreter = Interpreter::with_capabilities(&[], &Capabilities::pure());
+    assert_eq!(
+        interpreter.eval_expr("random()"),
+        Err(Error::Runtime(RuntimeError::CapabilityDisabled {
+            name: "random".to_string(),
+            capability: Capability::Time,
+            location: ast::location_in(0, 0, 6)
+        }))
+    );
+    interpreter.seed_random(7);
+    assert!(matches!(interpreter.eval_expr("random()"), Ok(Value::Number(_))));
+    assert!(interpreter.get_global("clock").is_none());
+}
+
+#[test]
+fn input_natives_need_stdin() {
+    let mut interpreter = Interpreter::with_capabilities(&[], &Capabilities::pure());
+    interpreter.set_input(Input::text("secret\n"));
+    for name in ["read_line", "read_all", "input"] {
+        assert!(interpreter.get_global(name).is_none(), "{} is defined", name);
+    }
+    assert!(matches!(
+        interpreter.eval_expr("read_line()"),
+        Err(Error::Runtime(RuntimeError::CapabilityDisabled {
+            capability: Capability::Stdin,
+            ..
+        }))
+    ));
+    let capabilities = Capabilities::pure().with(Capability::Stdin);
+    let mut interpreter = Interpreter::with_capabilities(&[], &capabilities);
+    interpreter.set_input(Input::text("secret\n"));
+    assert_eq!(interpreter.eval_expr("read_line()"), Ok(Value::from("secret")));
+}
+
+#[test]
+fn enabled_capability_defines_natives() {
+    let capabilities = Capabilities::pure().with(Capability::Time);
+    let interpreter = Interpreter::with_capabilities(&[], &capabilities);
+    assert!(interpreter.get_global("clock").is_some());
+    assert!(interpreter.get_global("exit").is_none());
+}
+
+#[test]
+fn fixed_clock() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_clock(Clock::Fixed(1234.0));
+    assert_eq!(interpreter.eval_expr("clock() + clock()"), Ok(Value::Number(2468.0)));
+}
+
+#[test]
+fn stepping_clock() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_clock(Clock::stepping(100.0, 10.0));
+    assert_eq!(
+        run_with(&mut interpreter, "print clock(); print clock(); print clock();"),
+        "100\n110\n120\n"
+    );
+}
+
+#[test]
+fn seeded_random_is_reproducible() {
+    let numbers = || {
+        let mut interpreter = Interpreter::new();
+        interpreter.seed_random(42);
+        run_with(&mut interpreter, "print random(); print random();")
+    };
+    let first = numbers();
+    assert_eq!(first, numbers());
+    for line in first.lines() {
+        let n: f64 = line.parse().unwrap();
+        assert!((0.0..1.0).contains(&n));
+    }
+}
+
+#[test]
+fn math_natives() {
+    let mut interpreter = Interpreter::new();
+    let eval = |interpreter: &mut Interpreter, source| match interpreter.eval_expr(source) {
+        Ok(Value::Number(n)) => n,
+        other => panic!("{}: {:?}", source, other),
+    };
+    assert_eq!(eval(&mut interpreter, "sqrt(16)"), 4.0);
+    assert_eq!(eval(&mut interpreter, "pow(2, 10)"), 1024.0);
+    assert_eq!(eval(&mut interpreter, "abs(-3)"), 3.0);
+    assert_eq!(eval(&mut interpreter, "floor(2.5) + ceil(2.5) + round(2.5)"), 8.0);
+    assert_eq!(eval(&mut interpreter, "min(3, 1, 2)"), 1.0);
+    assert_eq!(eval(&mut interpreter, "max(3, 1, 2)"), 3.0);
+    assert_eq!(eval(&mut interpreter, "atan2(1, 1) * 4"), std::f64::consts::PI);
+    assert_eq!(eval(&mut interpreter, "log(exp(2))"), 2.0);
+    assert_eq!(eval(&mut interpreter, "cos(PI)"), -1.0);
+    assert!(eval(&mut interpreter, "sqrt(-1)").is_nan());
+}
+
+#[test]
+fn math_natives_check_their_arguments() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(
+        interpreter.eval_expr("pow(2, \"x\")"),
+        Err(Error::Runtime(RuntimeError::TypeMismatch {
+            location: ast::location(7, 10)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("max(1, 2, nil)"),
+        Err(Error::Runtime(RuntimeError::TypeMismatch {
+            location: ast::location(10, 13)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("min()"),
+        Err(Error::Runtime(RuntimeError::ArityMismatch {
+            expected: Arity::AtLeast(1),
+            actual: 0,
+            location: ast::location(0, 3)
+        }))
+    );
+}
+
+#[test]
+fn string_natives() {
+    let source = r#"
+        var s = "héllo, wörld";
+        print len(s);
+        print substr(s, 1, 5);
+        print substr(s, 7, len(s));
+        print index_of(s, "wörld");
+        print index_of(s, "nope");
+        print contains(s, "llo");
+        print starts_with(s, "hé");
+        print ends_with(s, "rld");
+        print upper(s);
+        print lower("ÀB");
+        print trim("  x  ") + "|";
+        print split("a,b,,c", ",");
+        print split("añb", "");
+        print join(["a", "b", "c"], "-");
+        print replace("a.b.c", ".", "::");
+        print repeat("ab", 3);
+    "#;
+    assert_eq!(
+        run_string(source),
+        Ok(concat!(
+            "12\n",
+            "éllo\n",
+            "wörld\n",
+            "7\n",
+            "<nil>\n",
+            "true\n",
+            "true\n",
+            "true\n",
+            "HÉLLO, WÖRLD\n",
+            "àb\n",
+            "x|\n",
+            "[\"a\", \"b\", \"\", \"c\"]\n",
+            "[\"a\", \"ñ\", \"b\"]\n",
+            "a-b-c\n",
+            "a::b::c\n",
+            "ababab\n",
+        )
+        .to_string())
+    );
+}
+
+#[test]
+fn string_natives_check_their_arguments() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(
+        interpreter.eval_expr("substr(\"héllo\", 2, 6)"),
+        Err(Error::Runtime(RuntimeError::IndexOutOfRange {
+            index: 6.0,
+            length: 5,
+            location: ast::location(20, 21)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("substr(\"abc\", -1, 2)"),
+        Err(Error::Runtime(RuntimeError::IndexOutOfRange {
+            index: -1.0,
+            length: 3,
+            location: ast::location(14, 16)
+        }))
+    );
+    assert_eq!(interpreter.eval_expr("substr(\"abc\", 2, 1)"), Ok(Value::String(String::new())));
+    assert_eq!(
+        interpreter.eval_expr("join([\"a\", 1], \",\")"),
+        Err(Error::Runtime(RuntimeError::TypeMismatch {
+            location: ast::location(5, 13)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("repeat(\"a\", -1)"),
+        Err(Error::Runtime(RuntimeError::TypeMismatch {
+            location: ast::location(12, 14)
+        }))
+    );
+}
+
+#[test]
+fn repeat_refuses_to_build_huge_strings() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(
+        interpreter.eval_expr("repeat(\"ab\", 9223372036854775807)"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "the result would be longer than 1073741824 bytes".to_string(),
+            location: ast::location(13, 32)
+        }))
+    );
+    assert!(matches!(
+        interpreter.eval_expr("repeat(\"ab\", 1000000000000)"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument { .. }))
+    ));
+    interpreter.set_limits(Limits {
+        max_memory: Some(100_000),
+        ..Limits::default()
+    });
+    assert_eq!(
+        interpreter.eval_expr("repeat(\"ab\", 100000)"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "the result would go over the memory limit of 100000 bytes".to_string(),
+            location: ast::location(13, 19)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("len(repeat(\"ab\", 1000))"),
+        Ok(Value::Number(2000.0))
+    );
+}
+
+#[test]
+fn string_natives_check_the_length_before_building_it() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    interpreter
+        .run_source("var s = repeat(\"a\", 20000); var t = repeat(\"a\", 400000);")
+        .unwrap();
+    interpreter.set_limits(Limits {
+        max_memory: Some(1_000_000),
+        ..Limits::default()
+    });
+    for (source, index_location) in [
+        ("replace(s, \"a\", s)", ast::location(16, 17)),
+        ("re_replace(\"a\", s, s)", ast::location(19, 20)),
+        ("join([t, t], s)", ast::location(5, 11)),
+        ("format(\"{}{}\", t, t)", ast::location(18, 19)),
+    ] {
+        match interpreter.eval_expr(source) {
+            Err(Error::Runtime(RuntimeError::InvalidArgument { message, location })) => {
+                let expected = "the result would go over the memory limit of 1000000 bytes";
+                assert_eq!(message, expected, "{}", source);
+                assert_eq!(location, index_location, "{}", source);
+            }
+            other => panic!("{}: unexpected result {:?}", source, other),
+        }
+    }
+    assert_eq!(
+        interpreter.eval_expr("len(replace(s, \"a\", \"bb\"))"),
+        Ok(Value::Number(40000.0))
+    );
+    assert_eq!(
+        interpreter.eval_expr("re_replace(\"(a)(a)\", \"aaaa\", \"$2-$1\")"),
+        Ok(Value::from("a-aa-a"))
+    );
+}
+
+#[test]
+fn number_printing() {
+    let source = "print 3; print -0; print 2.5; print 0/0; print 1/0; print -1/0;
+        print 100000000000000000000; print 1 / 10000000;";
+    assert_eq!(
+        run_string(source),
+        Ok("3\n-0\n2.5\nnan\ninf\n-inf\n1e20\n1e-7\n".to_string())
+    );
+}
+
+#[test]
+fn conversions_between_numbers_and_strings() {
+    let source = r#"
+        print "n = " + str(1);
+        print str([1, "a"]);
+        print num(" 2.5 ") + 1;
+        print num("1e3");
+        print num("abc");
+        print num("inf");
+        print format("{} + {} = {:>4}", 1, 2, 3);
+        print format("{:.2}|{:<4}|{:^5}|{:03}", 3.14159, "ab", "c", 7);
+    "#;
+    assert_eq!(
+        run_string(source),
+        Ok(concat!(
+            "n = 1\n",
+            "[1, \"a\"]\n",
+            "3.5\n",
+            "1000\n",
+            "<nil>\n",
+            "<nil>\n",
+            "1 + 2 =    3\n",
+            "3.14|ab  |  c  |007\n",
+        )
+        .to_string())
+    );
+}
+
+#[test]
+fn format_errors_are_located() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(
+        interpreter.eval_expr("format(\"{} {}\", 1)"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "not enough arguments for format string".to_string(),
+            location: ast::location(7, 14)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("format(\"{}\", 1, 2)"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "too many arguments for format string".to_string(),
+            location: ast::location(16, 17)
+        }))
+    );
+    assert!(matches!(
+        interpreter.eval_expr("num(nil)"),
+        Err(Error::Runtime(RuntimeError::TypeMismatch { .. }))
+    ));
+}
+
+#[test]
+fn regex_natives() {
+    let source = r#"
+        var line = "2024-01-15 ERROR disk full; 2024-01-16 WARN slow";
+        print re_match("ERROR|FATAL", line);
+        print re_match("^WARN", line);
+        print re_find_all("\d{4}-\d{2}-\d{2}", line);
+        print re_replace("(\d{4})-(\d{2})-(\d{2})", line, "$3/$2/$1");
+        print re_split(";\s*", line);
+        print re_captures("(?P<level>[A-Z]+) (\w+)( x)?", line);
+        print re_captures("nope", line);
+    "#;
+    assert_eq!(
+        run_string(source),
+        Ok(concat!(
+            "true\n",
+            "false\n",
+            "[\"2024-01-15\", \"2024-01-16\"]\n",
+            "15/01/2024 ERROR disk full; 16/01/2024 WARN slow\n",
+            "[\"2024-01-15 ERROR disk full\", \"2024-01-16 WARN slow\"]\n",
+            "[\"ERROR disk\", \"ERROR\", \"disk\", <nil>]\n",
+            "<nil>\n",
+        )
+        .to_string())
+    );
+}
+
+#[test]
+fn invalid_regex_is_located() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(
+        interpreter.eval_expr("re_match(\"(a\", \"a\")"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "unclosed group".to_string(),
+            location: ast::location(9, 13)
+        }))
+    );
+}
+
+#[test]
+fn file_natives() {
+    let dir = std::env::temp_dir().join(format!("rlox-file-natives-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_global("dir", dir.to_str().unwrap().to_string());
+    let source = r#"
+        var path = dir + "/notes.txt";
+        print file_exists(path);
+        write_file(path, "one
+");
+        append_file(path, "two
+");
+        print file_exists(path);
+        print len(read_file(path));
+        print read_lines(path);
+        write_file(dir + "/b.txt", "");
+        print list_dir(dir);
+        remove_file(path);
+        print file_exists(path);
+    "#;
+    let output = run_with(&mut interpreter, source);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        output,
+        "false\ntrue\n8\n[\"one\", \"two\"]\n[\"b.txt\", \"notes.txt\"]\nfalse\n"
+    );
+}
+
+#[test]
+fn file_errors_are_located() {
+    let mut interpreter = Interpreter::new();
+    match interpreter.eval_expr("read_file(\"/nonexistent/file\")") {
+        Err(Error::Runtime(RuntimeError::Io { message, location })) => {
+            assert!(message.starts_with("cannot read '/nonexistent/file': "));
+            assert_eq!(location, ast::location(0, 9));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn file_natives_need_capabilities() {
+    let capabilities = Capabilities::pure().with(Capability::FsRead);
+    let mut interpreter = Interpreter::with_capabilities(&[], &capabilities);
+    assert_eq!(interpreter.eval_expr("file_exists(\"/\")"), Ok(Value::Boolean(true)));
+    assert!(matches!(
+        interpreter.eval_expr("write_file(\"x\", \"y\")"),
+        Err(Error::Runtime(RuntimeError::CapabilityDisabled {
+            capability: Capability::FsWrite,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn json_natives() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_global(
+        "text",
+        r#"{"name": "rlox", "tags": ["lox", "rust"], "stars": 42, "fork": false, "parent": null}"#,
+    );
+    let source = r#"
+        var data = json_parse(text);
+        print data["name"];
+        print data["tags"][1];
+        print data["stars"] + 1;
+        print data["parent"];
+        print json_stringify(data);
+        print json_stringify([1, [], "a"], 1);
+    "#;
+    assert_eq!(
+        run_with(&mut interpreter, source),
+        concat!(
+            "rlox\n",
+            "rust\n",
+            "43\n",
+            "<nil>\n",
+            "{\"fork\":false,\"name\":\"rlox\",\"parent\":null,\"stars\":42,\"tags\":[\"lox\",\"rust\"]}\n",
+            "[\n 1,\n [],\n \"a\"\n]\n",
+        )
+    );
+}
+
+#[test]
+fn json_errors_are_located() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_global("text", "[1,\n 2");
+    assert_eq!(
+        interpreter.eval_expr("json_parse(text)"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "invalid JSON at line 2, column 3: expected ',' or ']', found the end of the text"
+                .to_string(),
+            location: ast::location(11, 15)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("json_stringify([clock])"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "cannot convert a function to JSON".to_string(),
+            location: ast::location(15, 22)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("json_stringify([1], 1000000000000000)"),
+        Err(Error::Runtime(RuntimeError::InvalidArgument {
+            message: "the indent can be at most 10".to_string(),
+            location: ast::location(20, 36)
+        }))
+    );
+    assert_eq!(
+        interpreter.eval_expr("json_stringify([1], 10)"),
+        Ok(Value::from("[\n          1\n]"))
+    );
+    assert_eq!(
+        interpreter.eval_expr("json_stringify(1, 2, 3)"),
+        Err(Error::Runtime(RuntimeError::ArityMismatch {
+            expected: Arity::Between(1, 2),
+            actual: 3,
+            location: ast::location(0, 14)
+        }))
+    );
+}
+
+#[test]
+fn reading_input() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_input(Input::text("Ada\r\nsecond line\nthe\nrest\n"));
+    let source = r#"
+        var name = input("name? ");
+        print "hello, " + name;
+        print read_line();
+        print read_all();
+        print read_line();
+        print read_all() == "";
+    "#;
+    assert_eq!(
+        run_with(&mut interpreter, source),
+        "name? hello, Ada\nsecond line\nthe\nrest\n\n<nil>\ntrue\n"
+    );
+}
+
+/// Writes `files` into a new temporary directory, returning its path.
+#[cfg(test)]
+fn write_files(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("rlox-{}-{}", name, std::process::id()));
+    for (path, source) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, source).unwrap();
+    }
+    dir
+}
+
+/// Runs the script at `path`, returning what it printed.
+#[cfg(test)]
+fn run_script(path: &std::path::Path) -> Result<String, Error<'static>> {
+    let source: &'static str = Box::leak(std::fs::read_to_string(path).unwrap().into_boxed_str());
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    interpreter.run_script(path, source)?;
+    Ok(interpreter.take_output())
+}
+
+#[test]
+fn imports() {
+    let dir = write_files(
+        "imports",
+        &[
+            (
+                "main.lox",
+                r#"
+                import "lib/util.lox" as util;
+                import "lib/util.lox" as again;
+                print util.double(util.base);
+                print again.double(1);
+                print util.helper.name;
+                "#,
+            ),
+            (
+                "lib/util.lox",
+                r#"
+                import "helper.lox" as helper;
+                print "loading util";
+                var base = 21;
+                fun double(n) { return n * 2; }
+                "#,
+            ),
+            ("lib/helper.lox", r#"var name = "helper";"#),
+        ],
+    );
+    let output = run_script(&dir.join("main.lox"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.unwrap(), "loading util\n42\n2\nhelper\n");
+}
+
+#[test]
+fn modules_have_their_own_globals() {
+    let dir = write_files(
+        "module-globals",
+        &[
+            (
+                "main.lox",
+                r#"
+                var x = 2;
+                import "m.lox" as m;
+                print x;
+                print m.get();
+                print m.size;
+                "#,
+            ),
+            ("m.lox", r#"var x = 1; fun get() { return x; } var size = len("abc");"#),
+        ],
+    );
+    let output = run_script(&dir.join("main.lox"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.unwrap(), "2\n1\n3\n");
+}
+
+#[test]
+fn module_members() {
+    let dir = write_files("module-members", &[("m.lox", "var a = 1;")]);
+    let main = dir.join("main.lox");
+    std::fs::write(&main, "import \"m.lox\" as m;\nprint m.b;").unwrap();
+    let missing = run_script(&main);
+    std::fs::write(&main, "var n = 1;\nprint n.a;").unwrap();
+    let not_a_module = run_script(&main);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        missing,
+        Err(Error::Runtime(RuntimeError::IdentifierNotFound {
+            name: "b".to_string(),
+            location: ast::location(27, 30)
+        }))
+    );
+    assert_eq!(
+        not_a_module,
+        Err(Error::Runtime(RuntimeError::TypeMismatch {
+            location: ast::location(17, 18)
+        }))
+    );
+}
+
+#[test]
+fn import_cycles_are_errors() {
+    let dir = write_files(
+        "import-cycle",
+        &[
+            ("main.lox", r#"import "a.lox" as a;"#),
+            ("a.lox", r#"import "b.lox" as b;"#),
+            ("b.lox", r#"import "a.lox" as a;"#),
+        ],
+    );
+    let output = run_script(&dir.join("main.lox"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    match output {
+        Err(Error::Runtime(RuntimeError::ImportCycle {
+            cycle,
+            imports,
+            location,
+        })) => {
+            let names: Vec<&str> = cycle
+                .iter()
+                .map(|path| path.rsplit('/').next().unwrap())
+                .collect();
+            assert_eq!(names, vec!["a.lox", "b.lox", "a.lox"]);
+            // a.lox imports b.lox, which imports a.lox.
+            assert_eq!(imports, vec![ast::location_in(1, 0, 19)]);
+            assert_eq!(location, ast::location_in(2, 0, 19));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn import_errors_are_in_the_imported_file() {
+    let dir = write_files(
+        "import-errors",
+        &[
+            ("main.lox", "import \"bad.lox\" as bad;"),
+            ("bad.lox", "var x = ;\nvar y = ;"),
+        ],
+    );
+    let output = run_script(&dir.join("main.lox"));
+    std::fs::write(dir.join("main.lox"), "import \"missing.lox\" as m;").unwrap();
+    let missing = run_script(&dir.join("main.lox"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    match output {
+        Err(Error::Runtime(RuntimeError::Syntax { errors })) => {
+            let locations: Vec<_> = errors.iter().map(|e| e.location).collect();
+            assert_eq!(
+                locations,
+                vec![Some(ast::location_in(1, 8, 9)), Some(ast::location_in(1, 18, 19))]
+            );
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match missing {
+        Err(Error::Runtime(RuntimeError::Io { message, location })) => {
+            assert!(message.starts_with("cannot import '"));
+            assert_eq!(location, ast::location(0, 25));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn errors_are_located_in_the_source_they_happen_in() {
+    let mut interpreter = Interpreter::new();
+    interpreter.run_named("first", "fun f(x) { return -x; }").unwrap();
+    assert_eq!(
+        interpreter.eval_named("second", "f(\"a\")"),
+        Err(Error::Runtime(RuntimeError::TypeMismatch {
+            location: ast::location_in(0, 19, 20)
+        }))
+    );
+    let sources = interpreter.sources();
+    assert_eq!(sources.len(), 2);
+    assert_eq!(sources.name(1), Some("second"));
+    assert_eq!(sources.source(1), Some("f(\"a\")"));
+}
+
+#[test]
+fn only_sources_that_define_functions_are_kept() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    interpreter.run_named("f", "fun f() { return 1; }").unwrap();
+    for _ in 0..100 {
+        interpreter.run_source("print f();").unwrap();
+        interpreter.eval_expr("f() + 1").unwrap();
+        assert_eq!(interpreter.eval_or_run("line", "f()"), Ok(Some(Value::Number(1.0))));
+        assert_eq!(interpreter.eval_or_run("line", "var x = 2;"), Ok(None));
+    }
+    interpreter.run_named("g", "var g = fun () { return 2; };").unwrap();
+    let sources = interpreter.sources();
+    assert_eq!(sources.len(), 2);
+    assert_eq!(sources.name(0), Some("f"));
+    assert_eq!(sources.name(1), Some("g"));
+}
+
+#[test]
+fn every_syntax_error_is_reported() {
+    let source = "var a = ;\nfun f() { print 1 }\nprint 2 +;\nprint 3;";
+    match parse_program_string(source) {
+        Err(Error::Parse { file, errors }) => {
+            assert_eq!(file, 0);
+            let locations: Vec<_> = errors
+                .iter()
+                .map(|e| describe_parse_error(file, e).location)
+                .collect();
+            assert_eq!(
+                locations,
+                vec![
+                    Some(ast::location(8, 9)),
+                    Some(ast::location(28, 29)),
+                    Some(ast::location(39, 40))
+                ]
+            );
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn else_after_a_broken_if_is_not_reported_again() {
+    let source = "if (true) print 1; else print 2;\nprint 3 +;";
+    match parse_program_string(source) {
+        Err(Error::Parse { file, errors }) => {
+            let locations: Vec<_> = errors
+                .iter()
+                .map(|e| describe_parse_error(file, e).location)
+                .collect();
+            assert_eq!(
+                locations,
+                vec![Some(ast::location(10, 15)), Some(ast::location(42, 43))]
+            );
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn programs_with_syntax_errors_are_not_run() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    let result = interpreter.run_source("print 1;\nvar x = ;\nprint 2;");
+    assert!(matches!(result, Err(Error::Parse { ref errors, .. }) if errors.len() == 1));
+    assert_eq!(interpreter.take_output(), "");
+    assert_eq!(interpreter.globals().get("x"), None);
+}
+
+#[test]
+fn imports_need_fs_read() {
+    let dir = write_files(
+        "import-capability",
+        &[("m.lox", "print \"loaded\"; var secret = 1;")],
+    );
+    let main = dir.join("main.lox");
+    let source = "import \"m.lox\" as m;\nprint m.secret;";
+    let mut interpreter = Interpreter::with_capabilities(&[], &Capabilities::pure());
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    let result = interpreter.run_script(&main, source);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        result,
+        Err(Error::Runtime(RuntimeError::CapabilityDisabled {
+            name: "import".to_string(),
+            capability: Capability::FsRead,
+            location: ast::location(0, 19)
+        }))
+    );
+    assert_eq!(interpreter.take_output(), "");
+}
+
+#[test]
+fn top_level_return_is_an_error() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    assert_eq!(
+        interpreter.run_source("print 1;\nif (true) { return 1; }"),
+        Err(Error::Runtime(RuntimeError::ReturnOutsideFunction {
+            location: ast::location(21, 29)
+        }))
+    );
+    assert_eq!(interpreter.take_output(), "");
+    // Returning from a function is fine, of course.
+    assert_eq!(run_with(&mut interpreter, "fun f() { return 2; } print f();"), "2\n");
+}
+
+#[test]
+fn exit_is_not_reported() {
+    let mut interpreter = Interpreter::new();
+    let e = interpreter.run_source("exit(3);").unwrap_err();
+    assert_eq!(e, Error::Exit(3));
+    assert!(diagnostics(e).is_empty());
+    report_error(&interpreter.sources(), true, Error::Exit(3));
+}
+
+#[test]
+fn top_level_return_is_an_error_in_scripts_and_modules() {
+    let dir = write_files(
+        "import-return",
+        &[
+            ("main.lox", "return 1;"),
+            ("imports.lox", "import \"m.lox\" as m;"),
+            ("m.lox", "var a = 1;\nreturn a;"),
+        ],
+    );
+    let script = run_script(&dir.join("main.lox"));
+    let module = run_script(&dir.join("imports.lox"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        script,
+        Err(Error::Runtime(RuntimeError::ReturnOutsideFunction {
+            location: ast::location(0, 8)
+        }))
+    );
+    assert_eq!(
+        module,
+        Err(Error::Runtime(RuntimeError::ReturnOutsideFunction {
+            location: ast::location_in(1, 11, 19)
         }))
     );
 }
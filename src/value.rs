@@ -1,5 +1,14 @@
 use crate::ast;
 use crate::environment::Environment;
+use crate::error::NativeError;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// How deeply scripts may nest lists and maps. Measuring, printing and
+/// dropping a value all recurse on the Rust stack, once for each level.
+pub const MAX_NESTING: usize = 512;
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Value {
@@ -7,18 +16,221 @@ pub enum Value {
     Number(f64),
     Boolean(bool),
     String(String),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<BTreeMap<String, Value>>>),
     NativeFunction {
         name: String,
-        arity: usize,
-        // TODO: Result<Value, RuntimeError>, but that needs lifetime
-        // shenanigans.
-        fun: fn(Vec<Value>) -> Value,
+        arity: Arity,
+        fun: NativeFn,
     },
     // TODO: implement "return"
     LoxFunction {
         name: String,
         closure: Environment,
         params: Vec<String>,
-        body: Box<ast::Stmt>,
+        body: Rc<ast::Stmt>,
+    },
+    /// An imported file; `globals` holds what it defined.
+    Module {
+        path: String,
+        globals: Environment,
     },
 }
+
+impl Value {
+    pub fn list(values: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(values)))
+    }
+
+    pub fn map(entries: BTreeMap<String, Value>) -> Value {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+
+    /// The name of this value's type, for use in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Number(_) => "number",
+            Value::Boolean(_) => "boolean",
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::NativeFunction { .. } | Value::LoxFunction { .. } => "function",
+            Value::Module { .. } => "module",
+        }
+    }
+
+    /// Roughly how many bytes this value takes up, including what it refers
+    /// to. Shared lists and maps are counted each time, and a function's
+    /// closure isn't counted at all, since environments account for
+    /// themselves.
+    pub fn heap_size(&self) -> usize {
+        let contents = match self {
+            Value::String(s) => s.len(),
+            Value::List(values) => values.borrow().iter().map(Value::heap_size).sum(),
+            Value::Map(entries) => entries
+                .borrow()
+                .iter()
+                .map(|(k, v)| std::mem::size_of::<String>() + k.len() + v.heap_size())
+                .sum(),
+            Value::NativeFunction { name, .. } => name.len(),
+            Value::Module { path, .. } => path.len(),
+            Value::LoxFunction { name, params, .. } => {
+                name.len() + params.iter().map(|p| std::mem::size_of::<String>() + p.len()).sum::<usize>()
+            }
+            _ => 0,
+        };
+        std::mem::size_of::<Value>() + contents
+    }
+
+    /// How many lists and maps deep this value goes, counting no further
+    /// than `limit`.
+    pub fn nesting(&self, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+        let deepest = match self {
+            Value::List(values) => values.borrow().iter().map(|v| v.nesting(limit - 1)).max(),
+            Value::Map(entries) => entries.borrow().values().map(|v| v.nesting(limit - 1)).max(),
+            _ => return 0,
+        };
+        1 + deepest.unwrap_or(0)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+/// How many arguments a function takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    /// Between the two, inclusive.
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exactly(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+            Arity::Between(min, max) => (min..=max).contains(&count),
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::Between(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+/// The Rust implementation of a native function. It's a closure, rather than
+/// a plain function pointer, so that natives can capture state, such as the
+/// script's arguments.
+#[derive(Clone)]
+pub struct NativeFn(Rc<dyn Fn(Vec<Value>) -> Result<Value, NativeError>>);
+
+impl NativeFn {
+    pub fn new(fun: impl Fn(Vec<Value>) -> Result<Value, NativeError> + 'static) -> Self {
+        NativeFn(Rc::new(fun))
+    }
+
+    pub fn call(&self, argv: Vec<Value>) -> Result<Value, NativeError> {
+        (self.0)(argv)
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeFn").finish()
+    }
+}
+
+impl PartialEq for NativeFn {
+    fn eq(&self, other: &NativeFn) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// How numbers are printed: whole numbers without a fractional part, very
+/// large and very small ones with an exponent, and `nan`, `inf` and `-inf`.
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else if n != 0.0 && (n.abs() >= 1e16 || n.abs() < 1e-6) {
+        format!("{:e}", n)
+    } else {
+        format!("{}", n)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Value::*;
+        match self {
+            Nil => write!(f, "<nil>"),
+            Number(n) => f.write_str(&format_number(*n)),
+            Boolean(b) => write!(f, "{}", b),
+            String(s) => write!(f, "{}", s),
+            List(values) => {
+                write!(f, "[")?;
+                for (i, v) in values.borrow().iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    match v {
+                        String(s) => write!(f, "{:?}", s)?,
+                        _ => write!(f, "{}", v)?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in entries.borrow().iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: ", k)?;
+                    match v {
+                        String(s) => write!(f, "{:?}", s)?,
+                        _ => write!(f, "{}", v)?,
+                    }
+                }
+                write!(f, "}}")
+            }
+            NativeFunction { name, .. } => write!(f, "<fun {} (native)>", name),
+            LoxFunction { name, .. } => write!(f, "<fun {} (lox)>", name),
+            Module { path, .. } => write!(f, "<module {}>", path),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Shared support for the script-based tests. Scripts are annotated with
+//! comments describing what they should do:
+//!
+//! - `// expect: <text>` -- the script prints this line.
+//! - `// expect runtime error: <message>` -- the script fails at runtime, on
+//!   this line, with this message.
+//! - `// expect parse error: <message>` -- the script doesn't parse; the error
+//!   is on this line.
+//! - `// Error <message>` -- as used by the Crafting Interpreters test suite;
+//!   the same as `expect parse error`.
+//!
+//! A script can also ask to be run with extra command line options, with
+//! `// flags: <options>`, e.g. `// flags: --deterministic`, and be given input
+//! with `// stdin: <line>`; otherwise its stdin is empty.
+//!
+//! An error annotation may be prefixed with `[line N]` when the error is
+//! reported on some other line, e.g. `// [line 2] expect parse error: ...`.
+//! The test suite's `[java line N]` is treated the same as `[line N]`, and
+//! `[c line N]` (which only applies to clox) is ignored.
+
+use codespan_reporting::diagnostic::LabelStyle;
+use codespan_reporting::files::Files;
+use regex::Regex;
+use rlox::error;
+use rlox::{Clock, Error, Input, Interpreter, Output, RuntimeError};
+use std::path::{Path, PathBuf};
+
+/// The expected, or actual, result of running a script: the printed lines,
+/// followed by any errors, each formatted as `[line N] <kind> error: <message>`.
+pub type Transcript = Vec<String>;
+
+pub fn parse_expectations(source: &str) -> Transcript {
+    let output_pattern = Regex::new(r"// expect: ?(.*)").unwrap();
+    let error_pattern =
+        Regex::new(r"// (?:\[line (\d+)\] )?expect (parse|runtime) error: (.+)").unwrap();
+    let suite_error_pattern = Regex::new(r"// (?:\[(java |c )?line (\d+)\] )?(Error.*)").unwrap();
+
+    let mut output = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let this_line = i + 1;
+        if let Some(c) = output_pattern.captures(line) {
+            output.push(c[1].to_string());
+        } else if let Some(c) = error_pattern.captures(line) {
+            let n = c.get(1).map_or(this_line, |n| n.as_str().parse().unwrap());
+            errors.push(format!("[line {}] {} error: {}", n, &c[2], &c[3]));
+        } else if let Some(c) = suite_error_pattern.captures(line) {
+            if c.get(1).map(|l| l.as_str()) == Some("c ") {
+                continue;
+            }
+            let n = c.get(2).map_or(this_line, |n| n.as_str().parse().unwrap());
+            errors.push(format!("[line {}] parse error: {}", n, &c[3]));
+        }
+    }
+    output.extend(errors);
+    output
+}
+
+/// The options given by any `// flags:` annotations.
+pub fn parse_flags(source: &str) -> Vec<String> {
+    let flags_pattern = Regex::new(r"// flags: (.*)").unwrap();
+    source
+        .lines()
+        .filter_map(|line| flags_pattern.captures(line))
+        .flat_map(|c| {
+            c[1].split_whitespace()
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The input given by any `// stdin:` annotations, one line each.
+pub fn parse_stdin(source: &str) -> String {
+    let stdin_pattern = Regex::new(r"// stdin: ?(.*)").unwrap();
+    source
+        .lines()
+        .filter_map(|line| stdin_pattern.captures(line))
+        .map(|c| format!("{}\n", &c[1]))
+        .collect()
+}
+
+/// Runs the script at `path` in this process, as `rlox <path> <flags>` would.
+/// Only the options that the scripts use are understood.
+pub fn run(path: &Path, flags: &[String], stdin: &str) -> Transcript {
+    let source = std::fs::read_to_string(path).expect("read script");
+    let mut interpreter = Interpreter::new();
+    for flag in flags {
+        match flag.as_str() {
+            "--deterministic" => {
+                interpreter.set_clock(Clock::stepping(0.0, 1.0));
+                interpreter.seed_random(0);
+            }
+            "--gc-stress" => interpreter.set_gc_stress(true),
+            _ => panic!("unsupported flag '{}' in {:?}", flag, path),
+        }
+    }
+    interpreter.set_output(Output::Buffer(Vec::new()));
+    interpreter.set_input(Input::text(stdin));
+    let result = interpreter.run_script(path, &source);
+
+    let mut lines: Vec<String> = interpreter.take_output().lines().map(String::from).collect();
+    match result {
+        Ok(()) | Err(Error::Exit(0)) => {}
+        Err(Error::Exit(code)) => lines.push(format!("exited with {}", code)),
+        Err(e) => {
+            let kind = match e {
+                Error::Parse { .. }
+                | Error::Runtime(
+                    RuntimeError::Syntax { .. } | RuntimeError::ReturnOutsideFunction { .. },
+                ) => "parse",
+                _ => "runtime",
+            };
+            let sources = interpreter.sources();
+            for diagnostic in error::diagnostics(e) {
+                let primary = diagnostic
+                    .labels
+                    .iter()
+                    .find(|label| label.style == LabelStyle::Primary);
+                match primary {
+                    Some(label) => {
+                        let line = sources
+                            .line_index(label.file_id, label.range.start)
+                            .expect("error is in a known source");
+                        lines.push(format!(
+                            "[line {}] {} error: {}",
+                            line + 1,
+                            kind,
+                            diagnostic.message
+                        ));
+                    }
+                    None => lines.push(format!("{} error: {}", kind, diagnostic.message)),
+                }
+            }
+        }
+    }
+    lines
+}
+
+/// A line-based diff of `expected` against `actual`, using the longest common
+/// subsequence.
+pub fn diff(expected: &[String], actual: &[String]) -> String {
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            out += &format!("  {}\n", expected[i]);
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out += &format!("+ {}\n", actual[j]);
+            j += 1;
+        } else {
+            out += &format!("- {}\n", expected[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Returns every `.lox` file under `dir`, sorted.
+pub fn find_scripts(dir: &Path) -> Vec<PathBuf> {
+    fn visit(dir: &Path, scripts: &mut Vec<PathBuf>) {
+        for entry in std::fs::read_dir(dir).expect("read directory") {
+            let path = entry.expect("directory entry").path();
+            if path.is_dir() {
+                visit(&path, scripts);
+            } else if path.extension().is_some_and(|e| e == "lox") {
+                scripts.push(path);
+            }
+        }
+    }
+
+    let mut scripts = Vec::new();
+    visit(dir, &mut scripts);
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts found in {:?}", dir);
+    scripts
+}
+
+/// Calls `check` for each script, in parallel, and panics with the messages
+/// of any that fail.
+pub fn check_all<F>(scripts: &[PathBuf], check: F)
+where
+    F: Fn(&Path) -> Result<(), String> + Sync,
+{
+    let failures: Vec<String> = std::thread::scope(|s| {
+        let handles: Vec<_> = scripts
+            .iter()
+            .map(|path| {
+                let check = &check;
+                s.spawn(move || check(path).err())
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|h| h.join().expect("test thread"))
+            .collect()
+    });
+
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} scripts failed:\n\n{}",
+            failures.len(),
+            scripts.len(),
+            failures.join("\n")
+        );
+    }
+}
+
+/// The script's path, relative to `root`, for use in messages.
+pub fn name(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
@@ -0,0 +1,115 @@
+//! Runs the scripts in `conformance/`, which are taken from the test suite for
+//! the reference implementation of Lox, at
+//! https://github.com/munificent/craftinginterpreters/tree/master/test.
+//!
+//! rlox's error messages are worded differently from the reference
+//! implementation's, so only the kind and line number of each error is
+//! compared.
+//!
+//! Where rlox deliberately, or as yet, differs from the reference
+//! implementation, the script is listed in `DEVIATIONS`, and is expected to
+//! fail. If one of those starts passing, remove it from the list.
+
+mod common;
+
+use std::path::Path;
+
+const BLOCK_BODIES: &str = "'if', 'while' and 'for' bodies must be blocks";
+const NIL: &str = "nil prints as '<nil>'";
+const FUNCTIONS: &str = "functions print as '<fun name (lox)>'";
+const TRUTHINESS: &str = "conditions and '!' only accept booleans";
+const LOGICAL: &str = "'and' and 'or' aren't implemented";
+const RESOLVER: &str = "there's no resolver (chapter 11) yet";
+const INVALID_CHARACTER: &str = "the parser can't carry on after an invalid character";
+
+/// Scripts (or whole directories, with a trailing '/') that rlox is known to
+/// fail, and why.
+const DEVIATIONS: &[(&str, &str)] = &[
+    ("closure/assign_to_shadowed_later.lox", RESOLVER),
+    ("for/closure_in_body.lox", BLOCK_BODIES),
+    ("for/syntax.lox", BLOCK_BODIES),
+    ("function/empty_body.lox", NIL),
+    ("function/local_mutual_recursion.lox", BLOCK_BODIES),
+    ("function/local_recursion.lox", BLOCK_BODIES),
+    ("function/mutual_recursion.lox", BLOCK_BODIES),
+    ("function/print.lox", FUNCTIONS),
+    ("function/recursion.lox", BLOCK_BODIES),
+    ("if/dangling_else.lox", BLOCK_BODIES),
+    ("if/else.lox", BLOCK_BODIES),
+    ("if/if.lox", BLOCK_BODIES),
+    ("if/truth.lox", TRUTHINESS),
+    ("logical_operator/", LOGICAL),
+    ("nil/literal.lox", NIL),
+    ("operator/not.lox", TRUTHINESS),
+    ("return/after_else.lox", BLOCK_BODIES),
+    ("return/after_if.lox", BLOCK_BODIES),
+    ("return/after_while.lox", BLOCK_BODIES),
+    ("return/return_nil_if_no_value.lox", NIL),
+    ("unexpected_character.lox", INVALID_CHARACTER),
+    ("variable/collide_with_parameter.lox", RESOLVER),
+    ("variable/duplicate_local.lox", RESOLVER),
+    ("variable/duplicate_parameter.lox", RESOLVER),
+    ("variable/early_bound.lox", RESOLVER),
+    ("variable/redeclare_global.lox", NIL),
+    ("variable/uninitialized.lox", NIL),
+    ("variable/use_local_in_initializer.lox", RESOLVER),
+    ("while/closure_in_body.lox", BLOCK_BODIES),
+    ("while/syntax.lox", BLOCK_BODIES),
+];
+
+fn deviation(name: &str) -> Option<&'static str> {
+    DEVIATIONS.iter().find_map(|(pattern, reason)| {
+        let matches = match pattern.strip_suffix('/') {
+            Some(dir) => name.starts_with(dir) && name[dir.len()..].starts_with('/'),
+            None => name == *pattern,
+        };
+        if matches {
+            Some(*reason)
+        } else {
+            None
+        }
+    })
+}
+
+/// Error messages differ from the reference implementation, so drop them.
+fn without_messages(transcript: common::Transcript) -> common::Transcript {
+    transcript
+        .into_iter()
+        .map(|line| {
+            if line.starts_with("[line ") {
+                match line.find(" error") {
+                    Some(end) => line[..end + " error".len()].to_string(),
+                    None => line,
+                }
+            } else {
+                line
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn conformance() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let scripts = common::find_scripts(&root);
+
+    common::check_all(&scripts, |path| {
+        let name = common::name(&root, path);
+        let source = std::fs::read_to_string(path).expect("read script");
+        let expected = without_messages(common::parse_expectations(&source));
+        let actual = without_messages(common::run(
+            path,
+            &common::parse_flags(&source),
+            &common::parse_stdin(&source),
+        ));
+        match (expected == actual, deviation(&name)) {
+            (true, None) => Ok(()),
+            (false, Some(_)) => Ok(()),
+            (true, Some(reason)) => Err(format!(
+                "{}\nListed in DEVIATIONS ({}), but passes; remove it from the list.\n",
+                name, reason
+            )),
+            (false, None) => Err(format!("{}\n{}", name, common::diff(&expected, &actual))),
+        }
+    });
+}
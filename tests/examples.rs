@@ -0,0 +1,49 @@
+//! Runs every `.lox` file under `examples/` and compares its output against
+//! the annotations in its comments. See `common/mod.rs` for the format.
+
+mod common;
+
+use std::path::Path;
+
+#[test]
+fn examples() {
+    check_examples(&[]);
+}
+
+/// Examples that make so many calls that they take too long with
+/// `--gc-stress`.
+const TOO_SLOW_FOR_GC_STRESS: &[&str] = &["fibonacci-recursive.lox"];
+
+/// The same again, but collecting garbage as often as possible, to check that
+/// the collector doesn't free anything that's still in use.
+#[test]
+fn examples_with_gc_stress() {
+    check_examples(&["--gc-stress".to_string()]);
+}
+
+fn check_examples(extra_flags: &[String]) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut scripts = common::find_scripts(&root.join("examples"));
+    if !extra_flags.is_empty() {
+        scripts.retain(|path| {
+            !TOO_SLOW_FOR_GC_STRESS.contains(&&*common::name(&root.join("examples"), path))
+        });
+    }
+
+    common::check_all(&scripts, |path| {
+        let source = std::fs::read_to_string(path).expect("read script");
+        let expected = common::parse_expectations(&source);
+        let mut flags = common::parse_flags(&source);
+        flags.extend_from_slice(extra_flags);
+        let actual = common::run(path, &flags, &common::parse_stdin(&source));
+        if expected == actual {
+            Ok(())
+        } else {
+            Err(format!(
+                "{}\n{}",
+                common::name(root, path),
+                common::diff(&expected, &actual)
+            ))
+        }
+    });
+}